@@ -0,0 +1,294 @@
+//! Async counterparts of `Child` and its pipes for tokio, behind the `tokio` feature.
+
+use std::{
+    fmt::{Debug, Formatter, Result as FmtResult},
+    future::poll_fn,
+    io::{Error, ErrorKind, Result},
+    mem::zeroed,
+    os::fd::{AsRawFd, RawFd},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use libc::pid_t;
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, Interest, ReadBuf},
+    task::spawn_blocking,
+};
+
+use crate::{
+    anon_pipe::AnonPipe,
+    child::{ChildStderr, ChildStdin, ChildStdout},
+    cvt::cvt_r,
+    output::Output,
+    process::{ExitStatus, PidFd, Process},
+    strategy::LaunchInfo,
+};
+
+/// A child process driven by tokio, created with `Child::into_async()`. Its exit is noticed
+/// through the pidfd when the kernel supports them, otherwise a blocking thread waits for it
+/// (without reaping it, so the PID stays ours until `wait` collects the status).
+pub struct AsyncChild {
+    /// Registration of the pidfd with the reactor. The pidfd itself is owned by `handle`, so
+    /// this is declared first to be deregistered before the pidfd is closed.
+    exit: Option<AsyncFd<RawFd>>,
+    handle: Process,
+    /// The input stream to the child process
+    pub stdin: Option<AsyncChildStdin>,
+    /// The output stream from the child process
+    pub stdout: Option<AsyncChildStdout>,
+    /// The error stream from the child process
+    pub stderr: Option<AsyncChildStderr>,
+    launch: Option<LaunchInfo>,
+}
+
+impl AsyncChild {
+    pub(crate) fn new(
+        handle: Process,
+        stdin: Option<ChildStdin>,
+        stdout: Option<ChildStdout>,
+        stderr: Option<ChildStderr>,
+        launch: Option<LaunchInfo>,
+    ) -> Result<Self> {
+        let exit = match handle.pidfd() {
+            Some(pidfd) => Some(AsyncFd::with_interest(
+                pidfd.as_raw_fd(),
+                Interest::READABLE,
+            )?),
+            None => None,
+        };
+        Ok(Self {
+            exit,
+            handle,
+            stdin: stdin
+                .map(|p| AsyncPipe::new(p.0))
+                .transpose()?
+                .map(AsyncChildStdin),
+            stdout: stdout
+                .map(|p| AsyncPipe::new(p.0))
+                .transpose()?
+                .map(AsyncChildStdout),
+            stderr: stderr
+                .map(|p| AsyncPipe::new(p.0))
+                .transpose()?
+                .map(AsyncChildStderr),
+            launch,
+        })
+    }
+
+    /// How the program was launched: the strategy which worked and the flags of the memfd
+    /// it was executed from.
+    pub fn launch_info(&self) -> Option<&LaunchInfo> {
+        self.launch.as_ref()
+    }
+
+    /// Return the id of the child process, probably a PID
+    pub fn id(&self) -> u32 {
+        self.handle.id()
+    }
+
+    /// The pidfd of the child process, `None` if the kernel doesn't support pidfds.
+    pub fn pidfd(&self) -> Option<&PidFd> {
+        self.handle.pidfd()
+    }
+
+    /// Kill the child process. This doesn't wait for it to exit, `wait` does that.
+    pub fn kill(&mut self) -> Result<()> {
+        self.handle.kill()
+    }
+
+    /// Try and wait for the child process to exit, returning the exit status code if it has
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        self.handle.try_wait()
+    }
+
+    /// Wait for the child process to exit, returning the exit status code
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        drop(self.stdin.take());
+        loop {
+            if let Some(status) = self.handle.try_wait()? {
+                return Ok(status);
+            }
+            match &self.exit {
+                Some(exit) => exit.readable().await?.clear_ready(),
+                None => {
+                    let pid = self.handle.id() as pid_t;
+                    spawn_blocking(move || wait_exited(pid))
+                        .await
+                        .map_err(Error::other)??;
+                }
+            }
+        }
+    }
+
+    /// Wait for the child process to exit, returning the exit status code and the output
+    /// streams
+    pub async fn wait_with_output(mut self) -> Result<Output> {
+        drop(self.stdin.take());
+
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let (out, err) = (self.stdout.take(), self.stderr.take());
+        // Both pipes are drained together so the child can't block on a full one while we
+        // wait for the other to close
+        poll_fn(|cx| {
+            let out_done = match &out {
+                Some(out) => out.0.poll_drain(cx, &mut stdout)?.is_ready(),
+                None => true,
+            };
+            let err_done = match &err {
+                Some(err) => err.0.poll_drain(cx, &mut stderr)?.is_ready(),
+                None => true,
+            };
+            if out_done && err_done {
+                Poll::Ready(Ok::<_, Error>(()))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await?;
+
+        Ok(Output {
+            status: self.wait().await?,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+impl Debug for AsyncChild {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("AsyncChild")
+            .field("id", &self.handle.id())
+            .field("stdin", &self.stdin)
+            .field("stdout", &self.stdout)
+            .field("stderr", &self.stderr)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Block until `pid` has exited, leaving it to be reaped by `Process`.
+fn wait_exited(pid: pid_t) -> Result<()> {
+    let mut info: libc::siginfo_t = unsafe { zeroed() };
+    cvt_r(|| unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOWAIT,
+        )
+    })
+    .map(drop)
+}
+
+/// One of our ends of the child's pipes, nonblocking and registered with the reactor.
+struct AsyncPipe(AsyncFd<AnonPipe>);
+
+impl AsyncPipe {
+    fn new(pipe: AnonPipe) -> Result<Self> {
+        pipe.set_nonblocking(true)?;
+        AsyncFd::new(pipe).map(Self)
+    }
+
+    fn poll_read(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|pipe| pipe.get_ref().read(unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) if e.kind() == ErrorKind::Interrupted => {}
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => {}
+            }
+        }
+    }
+
+    fn poll_write(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            match guard.try_io(|pipe| pipe.get_ref().write(buf)) {
+                Ok(Err(e)) if e.kind() == ErrorKind::Interrupted => {}
+                Ok(res) => return Poll::Ready(res),
+                Err(_would_block) => {}
+            }
+        }
+    }
+
+    /// Read everything available into `dst`, ready once the pipe is closed.
+    fn poll_drain(&self, cx: &mut Context<'_>, dst: &mut Vec<u8>) -> Poll<Result<()>> {
+        let mut chunk = [0u8; 8192];
+        loop {
+            let mut buf = ReadBuf::new(&mut chunk);
+            ready!(self.poll_read(cx, &mut buf))?;
+            if buf.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            dst.extend_from_slice(buf.filled());
+        }
+    }
+}
+
+/// An async handle to a child process’s standard input (stdin).
+pub struct AsyncChildStdin(AsyncPipe);
+
+/// An async handle to a child process’s standard output (stdout).
+pub struct AsyncChildStdout(AsyncPipe);
+
+/// An async handle to a child process’s stderr.
+pub struct AsyncChildStderr(AsyncPipe);
+
+impl AsyncWrite for AsyncChildStdin {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        self.0.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        // The pipe is closed when the handle is dropped
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for AsyncChildStdout {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        self.0.poll_read(cx, buf)
+    }
+}
+
+impl AsyncRead for AsyncChildStderr {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        self.0.poll_read(cx, buf)
+    }
+}
+
+macro_rules! impl_pipe_handle {
+    ($($t:ident)*) => ($(
+        impl AsRawFd for $t {
+            #[inline]
+            fn as_raw_fd(&self) -> RawFd {
+                self.0 .0.as_raw_fd()
+            }
+        }
+
+        impl Debug for $t {
+            fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+                f.debug_struct(stringify!($t)).finish_non_exhaustive()
+            }
+        }
+    )*)
+}
+
+impl_pipe_handle! { AsyncChildStdin AsyncChildStdout AsyncChildStderr }
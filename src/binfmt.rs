@@ -0,0 +1,100 @@
+//! Detect programs the kernel runs through an interpreter: `#!` scripts, and programs handled
+//! by `binfmt_misc` like foreign architecture binaries registered for qemu-user. The kernel
+//! hands the interpreter the program as `/dev/fd/N`, which fails with `ENOENT` when the
+//! descriptor is closed on exec, so a memfd holding one of them must stay open in the program.
+
+use std::{
+    fs::{read_dir, read_to_string},
+    path::Path,
+    sync::OnceLock,
+};
+
+const BINFMT_MISC: &str = "/proc/sys/fs/binfmt_misc";
+
+/// The number of bytes of a program the kernel looks at to pick a binary format.
+pub(crate) const HEADER_LEN: usize = 256;
+
+/// An enabled `binfmt_misc` entry matching on magic bytes.
+#[derive(Debug)]
+struct Entry {
+    offset: usize,
+    magic: Vec<u8>,
+    mask: Vec<u8>,
+}
+
+impl Entry {
+    /// Parse the contents of `/proc/sys/fs/binfmt_misc/<name>`. Disabled entries and entries
+    /// matching on the file extension (which a memfd doesn't have) are skipped.
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        if lines.next()? != "enabled" {
+            return None;
+        }
+        let (mut offset, mut magic, mut mask) = (0, None, None);
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("offset", value)) => offset = value.parse().ok()?,
+                Some(("magic", value)) => magic = Some(decode_hex(value)?),
+                Some(("mask", value)) => mask = Some(decode_hex(value)?),
+                _ => {}
+            }
+        }
+        let magic = magic?;
+        let mask = mask.unwrap_or_else(|| vec![0xff; magic.len()]);
+        Some(Self {
+            offset,
+            magic,
+            mask,
+        })
+    }
+
+    fn matches(&self, header: &[u8]) -> bool {
+        let Some(bytes) = header.get(self.offset..self.offset + self.magic.len()) else {
+            return false;
+        };
+        bytes
+            .iter()
+            .zip(&self.magic)
+            .zip(&self.mask)
+            .all(|((byte, magic), mask)| byte & mask == magic & mask)
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The enabled entries, read once. Nothing is registered if `binfmt_misc` isn't mounted.
+fn entries() -> &'static [Entry] {
+    static ENTRIES: OnceLock<Vec<Entry>> = OnceLock::new();
+    ENTRIES.get_or_init(|| {
+        let dir = Path::new(BINFMT_MISC);
+        if read_to_string(dir.join("status")).map_or(true, |s| s.trim() != "enabled") {
+            return Vec::new();
+        }
+        let Ok(files) = read_dir(dir) else {
+            return Vec::new();
+        };
+        files
+            .flatten()
+            .filter(|file| !matches!(file.file_name().to_str(), Some("status" | "register")))
+            .filter_map(|file| Entry::parse(&read_to_string(file.path()).ok()?))
+            .collect()
+    })
+}
+
+/// Whether the kernel would run a program starting with `header` through an interpreter,
+/// either because it is a script or because it matches a `binfmt_misc` entry. The latter is
+/// also how programs are launched when we are ourselves running under qemu-user: the programs
+/// we launch are usually built for the same foreign architecture.
+pub(crate) fn needs_interpreter(header: &[u8]) -> bool {
+    is_script(header) || entries().iter().any(|entry| entry.matches(header))
+}
+
+/// Whether the program is a script starting with a `#!` line.
+fn is_script(header: &[u8]) -> bool {
+    header.starts_with(b"#!")
+}
@@ -1,10 +1,17 @@
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::io::{Error, ErrorKind, IoSlice, IoSliceMut, Read, Result, Write};
+use std::time::Duration;
+
+use libc::pid_t;
 
 use crate::anon_pipe::{read2, AnonPipe};
+#[cfg(feature = "tokio")]
+use crate::async_child::AsyncChild;
+use crate::cvt::cvt;
 use crate::output::Output;
-use crate::process::{ExitStatus, Process};
+use crate::process::{ExitStatus, PidFd, Process, Termination};
 use crate::stdio::StdioPipes;
+use crate::strategy::LaunchInfo;
 
 /// A child process created from a `MemFdExecutable` with handles to input and output streams
 pub struct Child {
@@ -15,6 +22,9 @@ pub struct Child {
     pub stdout: Option<ChildStdout>,
     /// The error stream from the child process
     pub stderr: Option<ChildStderr>,
+    launch: Option<LaunchInfo>,
+    /// The process group the child was moved into, if it was
+    group: Option<pid_t>,
 }
 
 impl Child {
@@ -24,19 +34,78 @@ impl Child {
             stdin: stdio.stdin.map(ChildStdin),
             stdout: stdio.stdout.map(ChildStdout),
             stderr: stdio.stderr.map(ChildStderr),
+            launch: None,
+            group: None,
         }
     }
 
+    pub(crate) fn with_process_group(mut self, group: Option<pid_t>) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub(crate) fn with_launch_info(mut self, launch: Option<LaunchInfo>) -> Self {
+        self.launch = launch;
+        self
+    }
+
+    /// How the program was launched: the strategy which worked and the flags of the memfd
+    /// it was executed from.
+    pub fn launch_info(&self) -> Option<&LaunchInfo> {
+        self.launch.as_ref()
+    }
+
     /// Kill the child process
     pub fn kill(&mut self) -> Result<()> {
         self.handle.kill()
     }
 
+    /// Send `signal` to the child process
+    pub fn signal(&mut self, signal: libc::c_int) -> Result<()> {
+        self.handle.signal(signal)
+    }
+
+    /// The process group the child was moved into with `process_group` or `setsid`, `None`
+    /// if it shares ours.
+    pub fn process_group(&self) -> Option<u32> {
+        self.group.map(|group| group as u32)
+    }
+
+    /// Send `signal` to every process in the child's process group. This works after the
+    /// child itself has exited, as long as any helper it started is left in the group. Fails
+    /// with `InvalidInput` if the child wasn't given a process group, rather than signalling
+    /// ours.
+    pub fn signal_group(&mut self, signal: libc::c_int) -> Result<()> {
+        let group = self.group.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "invalid argument: the child has no process group of its own",
+            )
+        })?;
+        cvt(unsafe { libc::killpg(group, signal) }).map(drop)
+    }
+
+    /// Kill the child process and everything it started with `SIGKILL`: its process group,
+    /// if it has one, and every descendant which can still be found under it, including
+    /// those which moved into groups of their own. Like `kill`, this doesn't wait for the
+    /// child to exit.
+    pub fn kill_tree(&mut self) -> Result<()> {
+        self.handle.kill_tree(self.group)
+    }
+
     /// Return the id of the child process, probably a PID
     pub fn id(&self) -> u32 {
         self.handle.id()
     }
 
+    /// The pidfd of the child process, `None` if the kernel doesn't support pidfds. `kill`,
+    /// `wait` and `try_wait` go through it when there is one, so they can't act on another
+    /// process which was given the PID after the child was reaped. It becomes readable when
+    /// the child exits, so it can be registered with `poll`, `epoll` and the like.
+    pub fn pidfd(&self) -> Option<&PidFd> {
+        self.handle.pidfd()
+    }
+
     /// Wait for the child process to exit, returning the exit status code
     pub fn wait(&mut self) -> Result<ExitStatus> {
         drop(self.stdin.take());
@@ -48,6 +117,48 @@ impl Child {
         self.handle.try_wait()
     }
 
+    /// Wait at most `timeout` for the child process to exit, returning the exit status code
+    /// if it did. Like `wait`, this closes stdin first.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<ExitStatus>> {
+        drop(self.stdin.take());
+        self.handle.wait_timeout(timeout)
+    }
+
+    /// Ask the child process to exit with `SIGTERM`, wait up to `grace` for it to do so and
+    /// kill it with `SIGKILL` if it doesn't. The result tells which of these ended it.
+    pub fn terminate(&mut self, grace: Duration) -> Result<Termination> {
+        self.terminate_with(libc::SIGTERM, grace)
+    }
+
+    /// Like `terminate`, but asks the child to exit with `signal` instead of `SIGTERM`, for
+    /// programs which shut down cleanly on something else, like `SIGINT` or `SIGHUP`.
+    pub fn terminate_with(&mut self, signal: libc::c_int, grace: Duration) -> Result<Termination> {
+        drop(self.stdin.take());
+        if let Some(status) = self.handle.try_wait()? {
+            return Ok(Termination::Exited(status));
+        }
+        self.handle.signal(signal)?;
+        if let Some(status) = self.handle.wait_timeout(grace)? {
+            return Ok(Termination::Terminated(status));
+        }
+        self.handle.kill()?;
+        Ok(Termination::Killed(self.handle.wait()?))
+    }
+
+    /// Hand the child over to tokio: the returned `AsyncChild` waits for it without blocking
+    /// a thread and its pipes implement `AsyncRead`/`AsyncWrite`. This must be called from
+    /// within a tokio runtime with IO enabled.
+    #[cfg(feature = "tokio")]
+    pub fn into_async(self) -> Result<AsyncChild> {
+        AsyncChild::new(
+            self.handle,
+            self.stdin,
+            self.stdout,
+            self.stderr,
+            self.launch,
+        )
+    }
+
     /// Wait for the child process to exit, returning the exit status code and the output
     /// streams
     pub fn wait_with_output(mut self) -> Result<Output> {
@@ -76,7 +187,7 @@ impl Child {
 }
 
 /// A handle to a child process’s standard input (stdin).
-pub struct ChildStdin(AnonPipe);
+pub struct ChildStdin(pub(crate) AnonPipe);
 
 impl std::os::fd::AsRawFd for ChildStdin {
     #[inline]
@@ -141,7 +252,7 @@ impl Debug for ChildStdin {
 }
 
 /// A handle to a child process’s standard output (stdout).
-pub struct ChildStdout(AnonPipe);
+pub struct ChildStdout(pub(crate) AnonPipe);
 
 impl std::os::fd::AsRawFd for ChildStdout {
     #[inline]
@@ -234,7 +345,7 @@ impl Debug for ChildStdout {
 }
 
 /// A handle to a child process’s stderr.
-pub struct ChildStderr(AnonPipe);
+pub struct ChildStderr(pub(crate) AnonPipe);
 
 impl std::os::fd::AsRawFd for ChildStderr {
     #[inline]
@@ -0,0 +1,157 @@
+//! The different places the code of an in-memory executable can live. Borrowed slices keep
+//! working like they always did, while owned and shared buffers let a `MemFdExecutable` be
+//! `'static`, sent to other threads and cloned without copying the code.
+
+use std::{
+    borrow::Cow,
+    fmt::{Debug, Formatter, Result as FmtResult},
+    sync::Arc,
+};
+
+use crate::image::MemFdImage;
+
+/// The code of an executable. You usually don't need to build one of these yourself, anything
+/// that converts into a `CodeSource` can be passed straight to `MemFdExecutable::new`:
+///
+/// * `&[u8]`, `&Vec<u8>` and `&[u8; N]` (for example the result of `include_bytes!()`) are
+///   borrowed
+/// * `Vec<u8>`, `Box<[u8]>` and `Arc<[u8]>` are owned, and shared between clones of the
+///   executable without copying
+/// * `MemFdImage` runs an already sealed memfd
+///
+/// Any other buffer type, like `bytes::Bytes`, can be used with `CodeSource::shared`.
+#[derive(Clone)]
+pub struct CodeSource<'a>(Inner<'a>);
+
+#[derive(Clone)]
+enum Inner<'a> {
+    Borrowed(&'a [u8]),
+    Shared(Arc<dyn AsRef<[u8]> + Send + Sync>),
+    Image(MemFdImage),
+}
+
+impl<'a> CodeSource<'a> {
+    /// Use any owned buffer as the code, without copying it. The buffer is reference counted
+    /// and shared between clones of the executable.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use memfd_exec::{CodeSource, MemFdExecutable};
+    ///
+    /// // Stand-in for something like `bytes::Bytes`
+    /// struct Download(Vec<u8>);
+    ///
+    /// impl AsRef<[u8]> for Download {
+    ///     fn as_ref(&self) -> &[u8] {
+    ///         &self.0
+    ///     }
+    /// }
+    ///
+    /// let code = Download(std::fs::read("/bin/ls").unwrap());
+    /// let ls = MemFdExecutable::new("ls", CodeSource::shared(code));
+    /// ```
+    pub fn shared<T: AsRef<[u8]> + Send + Sync + 'static>(code: T) -> Self {
+        Self(Inner::Shared(Arc::new(code)))
+    }
+
+    /// The code as a byte slice, if it lives in a buffer rather than a memfd image.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self.0 {
+            Inner::Borrowed(code) => Some(code),
+            Inner::Shared(ref code) => Some((**code).as_ref()),
+            Inner::Image(_) => None,
+        }
+    }
+
+    /// The memfd image backing the code, if there is one.
+    pub fn as_image(&self) -> Option<&MemFdImage> {
+        match self.0 {
+            Inner::Image(ref image) => Some(image),
+            _ => None,
+        }
+    }
+
+    /// The size of the code in bytes.
+    pub fn len(&self) -> usize {
+        match self.0 {
+            Inner::Image(ref image) => image.len(),
+            _ => self.as_bytes().map_or(0, <[u8]>::len),
+        }
+    }
+
+    /// Whether the code is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Debug for CodeSource<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.0 {
+            Inner::Borrowed(code) => f.debug_tuple("Borrowed").field(&code.len()).finish(),
+            Inner::Shared(ref code) => f
+                .debug_tuple("Shared")
+                .field(&(**code).as_ref().len())
+                .finish(),
+            Inner::Image(ref image) => f.debug_tuple("Image").field(image).finish(),
+        }
+    }
+}
+
+impl<'a> From<&'a [u8]> for CodeSource<'a> {
+    fn from(code: &'a [u8]) -> Self {
+        Self(Inner::Borrowed(code))
+    }
+}
+
+impl<'a, const N: usize> From<&'a [u8; N]> for CodeSource<'a> {
+    fn from(code: &'a [u8; N]) -> Self {
+        Self(Inner::Borrowed(code))
+    }
+}
+
+impl<'a> From<&'a Vec<u8>> for CodeSource<'a> {
+    fn from(code: &'a Vec<u8>) -> Self {
+        Self(Inner::Borrowed(code))
+    }
+}
+
+impl From<Vec<u8>> for CodeSource<'_> {
+    fn from(code: Vec<u8>) -> Self {
+        Self::shared(code)
+    }
+}
+
+impl From<Box<[u8]>> for CodeSource<'_> {
+    fn from(code: Box<[u8]>) -> Self {
+        Self::shared(code)
+    }
+}
+
+impl From<Arc<[u8]>> for CodeSource<'_> {
+    fn from(code: Arc<[u8]>) -> Self {
+        Self::shared(code)
+    }
+}
+
+impl<'a> From<Cow<'a, [u8]>> for CodeSource<'a> {
+    fn from(code: Cow<'a, [u8]>) -> Self {
+        match code {
+            Cow::Borrowed(code) => code.into(),
+            Cow::Owned(code) => code.into(),
+        }
+    }
+}
+
+impl From<MemFdImage> for CodeSource<'_> {
+    fn from(image: MemFdImage) -> Self {
+        Self(Inner::Image(image))
+    }
+}
+
+impl From<&MemFdImage> for CodeSource<'_> {
+    fn from(image: &MemFdImage) -> Self {
+        Self(Inner::Image(image.clone()))
+    }
+}
@@ -34,18 +34,6 @@ impl CommandEnv {
         result
     }
 
-    pub fn is_unchanged(&self) -> bool {
-        !self.clear && self.vars.is_empty()
-    }
-
-    pub fn capture_if_changed(&self) -> Option<BTreeMap<OsString, OsString>> {
-        if self.is_unchanged() {
-            None
-        } else {
-            Some(self.capture())
-        }
-    }
-
     // The following functions build up changes
     pub fn set(&mut self, key: &OsStr, value: &OsStr) {
         let key = OsString::from(key);
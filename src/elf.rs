@@ -0,0 +1,473 @@
+//! A minimal ELF header parser, used to describe a program and to check it can run on this
+//! host before anything is forked.
+
+use std::{
+    ffi::OsStr,
+    fmt::{Display, Formatter, Result as FmtResult},
+    os::unix::prelude::OsStrExt,
+    path::PathBuf,
+};
+
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+
+const EM_386: u16 = 3;
+const EM_MIPS: u16 = 8;
+const EM_PPC: u16 = 20;
+const EM_PPC64: u16 = 21;
+const EM_S390: u16 = 22;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+const EM_LOONGARCH: u16 = 258;
+
+/// Whether an ELF file is 32 or 64 bit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfClass {
+    /// `ELFCLASS32`
+    Elf32,
+    /// `ELFCLASS64`
+    Elf64,
+}
+
+/// The byte order of an ELF file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    /// `ELFDATA2LSB`
+    Little,
+    /// `ELFDATA2MSB`
+    Big,
+}
+
+/// The `e_type` of an ELF file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfType {
+    /// `ET_EXEC`, a position dependent executable
+    Executable,
+    /// `ET_DYN`, a position independent executable or a shared library
+    Dynamic,
+    /// Anything else, like a relocatable object or a core dump, which can't be executed
+    Other(u16),
+}
+
+/// A summary of the ELF header of a program.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::fs::read;
+///
+/// use memfd_exec::ElfInfo;
+///
+/// let info = ElfInfo::parse(&read("/bin/ls").unwrap()).unwrap();
+/// println!("launching {info}");
+/// assert!(info.is_native());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ElfInfo {
+    /// 32 or 64 bit
+    pub class: ElfClass,
+    /// The byte order
+    pub endianness: Endianness,
+    /// The `e_machine` of the program, see `machine_name`
+    pub machine: u16,
+    /// The `e_type` of the program
+    pub elf_type: ElfType,
+    /// The entry point address
+    pub entry: u64,
+    /// The program interpreter (dynamic loader) named by `PT_INTERP`, `None` for static
+    /// programs
+    pub interpreter: Option<PathBuf>,
+    /// Where the program headers are in the file, and how many there are
+    phoff: u64,
+    phnum: u16,
+    phentsize: u16,
+    /// The `PT_LOAD` segments
+    segments: Vec<Segment>,
+}
+
+/// A `PT_LOAD` program header: `filesz` bytes at `offset` in the file are mapped at `vaddr`,
+/// followed by zeros up to `memsz`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Segment {
+    pub(crate) offset: u64,
+    pub(crate) vaddr: u64,
+    pub(crate) filesz: u64,
+    pub(crate) memsz: u64,
+    /// `PF_X`, `PF_W` and `PF_R`
+    pub(crate) flags: u32,
+}
+
+/// Why a program can't be run on this host, found by parsing its ELF header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ElfError {
+    /// The program doesn't start with the ELF magic
+    NotElf,
+    /// The file ends before the headers do
+    Truncated,
+    /// The ELF class is neither 32 nor 64 bit
+    BadClass(u8),
+    /// The byte order is neither little nor big endian
+    BadEndianness(u8),
+    /// The ELF file is not an executable, like a relocatable object or a core dump
+    NotExecutable(u16),
+    /// The program is built for another architecture (`e_machine`) than this host, and no
+    /// `binfmt_misc` emulator is registered for it
+    ForeignArch {
+        /// The `e_machine` of the program
+        machine: u16,
+    },
+    /// The program interpreter (dynamic loader) of the program doesn't exist on this host
+    MissingInterpreter(PathBuf),
+}
+
+impl ElfInfo {
+    /// Parse the ELF header and program headers at the start of `code`.
+    pub fn parse(code: &[u8]) -> Result<Self, ElfError> {
+        Self::parse_with(|offset, len| {
+            let start = usize::try_from(offset).ok()?;
+            code.get(start..start.checked_add(len)?).map(<[u8]>::to_vec)
+        })
+    }
+
+    /// Parse the ELF header with `read_at(offset, len)`, which returns `None` when the file
+    /// is too short.
+    pub(crate) fn parse_with<F: Fn(u64, usize) -> Option<Vec<u8>>>(
+        read_at: F,
+    ) -> Result<Self, ElfError> {
+        let ident = read_at(0, 16).ok_or(ElfError::NotElf)?;
+        if ident[..4] != *b"\x7fELF" {
+            return Err(ElfError::NotElf);
+        }
+        let class = match ident[4] {
+            1 => ElfClass::Elf32,
+            2 => ElfClass::Elf64,
+            other => return Err(ElfError::BadClass(other)),
+        };
+        let endianness = match ident[5] {
+            1 => Endianness::Little,
+            2 => Endianness::Big,
+            other => return Err(ElfError::BadEndianness(other)),
+        };
+        let fields = Fields { class, endianness };
+
+        let header_len = match class {
+            ElfClass::Elf32 => 52,
+            ElfClass::Elf64 => 64,
+        };
+        let header = read_at(0, header_len).ok_or(ElfError::Truncated)?;
+        let elf_type = match fields.u16(&header, 16) {
+            ET_EXEC => ElfType::Executable,
+            ET_DYN => ElfType::Dynamic,
+            other => ElfType::Other(other),
+        };
+        let machine = fields.u16(&header, 18);
+        let entry = fields.addr(&header, 24);
+        let (phoff, phentsize, phnum) = match class {
+            ElfClass::Elf32 => (
+                fields.u32(&header, 28) as u64,
+                fields.u16(&header, 42),
+                fields.u16(&header, 44),
+            ),
+            ElfClass::Elf64 => (
+                fields.u64(&header, 32),
+                fields.u16(&header, 54),
+                fields.u16(&header, 56),
+            ),
+        };
+
+        let mut interpreter = None;
+        let mut segments = Vec::new();
+        for i in 0..phnum as u64 {
+            let phdr = read_at(phoff + i * phentsize as u64, phentsize as usize)
+                .filter(|phdr| phdr.len() >= 32)
+                .ok_or(ElfError::Truncated)?;
+            if fields.u32(&phdr, 0) == PT_LOAD {
+                segments.push(match class {
+                    ElfClass::Elf32 => Segment {
+                        offset: fields.u32(&phdr, 4) as u64,
+                        vaddr: fields.u32(&phdr, 8) as u64,
+                        filesz: fields.u32(&phdr, 16) as u64,
+                        memsz: fields.u32(&phdr, 20) as u64,
+                        flags: fields.u32(&phdr, 24),
+                    },
+                    ElfClass::Elf64 if phdr.len() >= 48 => Segment {
+                        offset: fields.u64(&phdr, 8),
+                        vaddr: fields.u64(&phdr, 16),
+                        filesz: fields.u64(&phdr, 32),
+                        memsz: fields.u64(&phdr, 40),
+                        flags: fields.u32(&phdr, 4),
+                    },
+                    ElfClass::Elf64 => return Err(ElfError::Truncated),
+                });
+                continue;
+            }
+            if fields.u32(&phdr, 0) != PT_INTERP {
+                continue;
+            }
+            let (offset, len) = match class {
+                ElfClass::Elf32 => (fields.u32(&phdr, 4) as u64, fields.u32(&phdr, 16) as u64),
+                ElfClass::Elf64 => (fields.u64(&phdr, 8), fields.u64(&phdr, 32)),
+            };
+            let path = read_at(offset, len as usize).ok_or(ElfError::Truncated)?;
+            let path = path.split(|&b| b == 0).next().unwrap_or_default();
+            interpreter = Some(PathBuf::from(OsStr::from_bytes(path)));
+        }
+
+        Ok(Self {
+            class,
+            endianness,
+            machine,
+            elf_type,
+            entry,
+            interpreter,
+            phoff,
+            phnum,
+            phentsize,
+            segments,
+        })
+    }
+
+    /// Whether the program is built for exactly the architecture and word size of this
+    /// process, so it can be mapped into it.
+    pub(crate) fn is_same_arch(&self) -> bool {
+        let class = if cfg!(target_pointer_width = "64") {
+            ElfClass::Elf64
+        } else {
+            ElfClass::Elf32
+        };
+        native_machines().and_then(<[u16]>::first) == Some(&self.machine)
+            && self.class == class
+            && self.endianness == NATIVE_ENDIANNESS
+    }
+
+    /// The `PT_LOAD` segments of the program.
+    pub(crate) fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// The offset of the program headers in the file, their number and their size.
+    pub(crate) fn program_headers(&self) -> (u64, u16, u16) {
+        (self.phoff, self.phnum, self.phentsize)
+    }
+
+    /// The name of the architecture of the program, like `x86_64` or `aarch64`.
+    pub fn machine_name(&self) -> &'static str {
+        machine_name(self.machine)
+    }
+
+    /// Whether the program is built for the architecture of this host.
+    pub fn is_native(&self) -> bool {
+        is_native_machine(self.machine)
+            && self.endianness == NATIVE_ENDIANNESS
+            && (self.class == ElfClass::Elf32 || cfg!(target_pointer_width = "64"))
+    }
+
+    /// Whether the program is linked statically, i.e. doesn't need a program interpreter.
+    pub fn is_static(&self) -> bool {
+        self.interpreter.is_none()
+    }
+
+    /// Check the program can be executed on this host: it must be an executable for this
+    /// architecture (or `emulated`) whose program interpreter exists (or is `bundled`).
+    pub(crate) fn check(&self, emulated: bool, bundled: bool) -> Result<(), ElfError> {
+        if let ElfType::Other(elf_type) = self.elf_type {
+            return Err(ElfError::NotExecutable(elf_type));
+        }
+        if !self.is_native() && !emulated {
+            return Err(ElfError::ForeignArch {
+                machine: self.machine,
+            });
+        }
+        match self.interpreter {
+            Some(ref interpreter) if !bundled && !interpreter.exists() => {
+                Err(ElfError::MissingInterpreter(interpreter.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Display for ElfInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let class = match self.class {
+            ElfClass::Elf32 => "ELF32",
+            ElfClass::Elf64 => "ELF64",
+        };
+        let endianness = match self.endianness {
+            Endianness::Little => "LSB",
+            Endianness::Big => "MSB",
+        };
+        let elf_type = match self.elf_type {
+            ElfType::Executable => "executable".to_string(),
+            ElfType::Dynamic => "pie executable".to_string(),
+            ElfType::Other(other) => format!("type {other}"),
+        };
+        write!(
+            f,
+            "{class} {endianness} {elf_type}, {}",
+            self.machine_name()
+        )?;
+        match self.interpreter {
+            Some(ref interpreter) => write!(f, ", interpreter {}", interpreter.display()),
+            None => write!(f, ", statically linked"),
+        }
+    }
+}
+
+impl ElfError {
+    /// Serialize the error as a tag and a value, followed by the path for
+    /// `MissingInterpreter`.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let (tag, value): (u32, u32) = match *self {
+            ElfError::NotElf => (1, 0),
+            ElfError::Truncated => (2, 0),
+            ElfError::BadClass(class) => (3, class as u32),
+            ElfError::BadEndianness(endianness) => (4, endianness as u32),
+            ElfError::NotExecutable(elf_type) => (5, elf_type as u32),
+            ElfError::ForeignArch { machine } => (6, machine as u32),
+            ElfError::MissingInterpreter(_) => (7, 0),
+        };
+        let mut bytes = tag.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&value.to_be_bytes());
+        if let ElfError::MissingInterpreter(ref path) = *self {
+            bytes.extend_from_slice(path.as_os_str().as_bytes());
+        }
+        bytes
+    }
+
+    /// The inverse of `encode`.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let tag = u32::from_be_bytes(bytes[..4].try_into().unwrap());
+        let value = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+        Some(match tag {
+            1 => ElfError::NotElf,
+            2 => ElfError::Truncated,
+            3 => ElfError::BadClass(value as u8),
+            4 => ElfError::BadEndianness(value as u8),
+            5 => ElfError::NotExecutable(value as u16),
+            6 => ElfError::ForeignArch {
+                machine: value as u16,
+            },
+            7 => ElfError::MissingInterpreter(PathBuf::from(OsStr::from_bytes(&bytes[8..]))),
+            _ => return None,
+        })
+    }
+}
+
+impl Display for ElfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match *self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Truncated => write!(f, "truncated ELF"),
+            ElfError::BadClass(class) => write!(f, "unknown ELF class {class}"),
+            ElfError::BadEndianness(endianness) => {
+                write!(f, "unknown ELF byte order {endianness}")
+            }
+            ElfError::NotExecutable(elf_type) => {
+                write!(f, "ELF file of type {elf_type} is not executable")
+            }
+            ElfError::ForeignArch { machine } => write!(
+                f,
+                "{} binary on {} host",
+                machine_name(machine),
+                std::env::consts::ARCH
+            ),
+            ElfError::MissingInterpreter(ref path) => {
+                write!(f, "program interpreter {} does not exist", path.display())
+            }
+        }
+    }
+}
+
+/// Reads header fields in the byte order of the file.
+struct Fields {
+    class: ElfClass,
+    endianness: Endianness,
+}
+
+impl Fields {
+    fn u16(&self, bytes: &[u8], offset: usize) -> u16 {
+        let bytes = bytes[offset..offset + 2].try_into().unwrap();
+        match self.endianness {
+            Endianness::Little => u16::from_le_bytes(bytes),
+            Endianness::Big => u16::from_be_bytes(bytes),
+        }
+    }
+
+    fn u32(&self, bytes: &[u8], offset: usize) -> u32 {
+        let bytes = bytes[offset..offset + 4].try_into().unwrap();
+        match self.endianness {
+            Endianness::Little => u32::from_le_bytes(bytes),
+            Endianness::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    fn u64(&self, bytes: &[u8], offset: usize) -> u64 {
+        let bytes = bytes[offset..offset + 8].try_into().unwrap();
+        match self.endianness {
+            Endianness::Little => u64::from_le_bytes(bytes),
+            Endianness::Big => u64::from_be_bytes(bytes),
+        }
+    }
+
+    fn addr(&self, bytes: &[u8], offset: usize) -> u64 {
+        match self.class {
+            ElfClass::Elf32 => self.u32(bytes, offset) as u64,
+            ElfClass::Elf64 => self.u64(bytes, offset),
+        }
+    }
+}
+
+const NATIVE_ENDIANNESS: Endianness = if cfg!(target_endian = "little") {
+    Endianness::Little
+} else {
+    Endianness::Big
+};
+
+fn machine_name(machine: u16) -> &'static str {
+    match machine {
+        EM_386 => "x86",
+        EM_MIPS => "mips",
+        EM_PPC => "powerpc",
+        EM_PPC64 => "powerpc64",
+        EM_S390 => "s390x",
+        EM_ARM => "arm",
+        EM_X86_64 => "x86_64",
+        EM_AARCH64 => "aarch64",
+        EM_RISCV => "riscv",
+        EM_LOONGARCH => "loongarch",
+        _ => "unknown",
+    }
+}
+
+/// The machines this host runs programs for natively, its own first, `None` if we don't
+/// know. 64-bit hosts usually run the programs of their 32-bit counterpart too.
+fn native_machines() -> Option<&'static [u16]> {
+    Some(match std::env::consts::ARCH {
+        "x86_64" => &[EM_X86_64, EM_386],
+        "x86" => &[EM_386],
+        "aarch64" => &[EM_AARCH64, EM_ARM],
+        "arm" => &[EM_ARM],
+        "riscv64" | "riscv32" => &[EM_RISCV],
+        "powerpc64" => &[EM_PPC64, EM_PPC],
+        "powerpc" => &[EM_PPC],
+        "s390x" => &[EM_S390],
+        "mips" | "mips64" => &[EM_MIPS],
+        "loongarch64" => &[EM_LOONGARCH],
+        _ => return None,
+    })
+}
+
+/// Whether this host runs programs built for `machine` natively.
+fn is_native_machine(machine: u16) -> bool {
+    native_machines().is_none_or(|native| native.contains(&machine))
+}
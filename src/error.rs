@@ -0,0 +1,262 @@
+//! The error type describing which stage of launching an in-memory executable failed.
+
+use std::{
+    error::Error as StdError,
+    ffi::OsStr,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::{Error, ErrorKind},
+    os::unix::prelude::OsStrExt,
+    path::PathBuf,
+};
+
+use crate::elf::ElfError;
+
+/// An error which occurred while launching an in-memory executable. Every variant records the
+/// stage that failed and, where there is one, the underlying `errno`.
+///
+/// The public API returns `std::io::Error` for parity with `std::process::Command`. The
+/// `MemFdExecError` is stored inside it and can be recovered with `MemFdExecError::from_io`:
+///
+/// ```no_run
+/// use memfd_exec::{MemFdExecError, MemFdExecutable};
+///
+/// let err = MemFdExecutable::new("broken", vec![0u8; 64]).spawn().unwrap_err();
+/// match MemFdExecError::from_io(&err) {
+///     Some(MemFdExecError::FallbackTmpfile { dir, errno }) => {
+///         eprintln!("could not run from {}: errno {}", dir.display(), errno)
+///     }
+///     Some(other) => eprintln!("launch failed: {other}"),
+///     None => eprintln!("some other I/O error: {err}"),
+/// }
+/// ```
+///
+/// An `io::Error` can't hold both a payload and an OS error code, so `raw_os_error()` is
+/// `None` on the errors returned for a failed launch, even when a system call failed. Their
+/// `kind()` still matches the errno, and the errno itself is available from
+/// `MemFdExecError::errno`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MemFdExecError {
+    /// `memfd_create` failed
+    MemfdCreate(i32),
+    /// Writing the program into a memfd or tmpfile failed
+    WriteImage(i32),
+    /// Sealing a memfd failed
+    Seal(i32),
+    /// Executing the memfd failed
+    Fexecve(i32),
+    /// Creating, writing or executing the fallback tmpfile in `dir` failed
+    FallbackTmpfile {
+        /// The directory the tmpfile was created in
+        dir: PathBuf,
+        /// The underlying error
+        errno: i32,
+    },
+    /// Changing to the working directory of the program failed
+    Chdir(i32),
+    /// Redirecting one of the stdio streams of the program failed
+    Dup2(i32),
+    /// Resetting the signal mask or dispositions of the program failed
+    Signals(i32),
+    /// Forking the child process failed
+    Fork(i32),
+    /// An argument, environment variable or the working directory contained a NUL byte
+    NulInArgument,
+    /// The launch strategy chain was empty, or `NO_MEMFDEXEC=1` ruled out all of it
+    NoLaunchStrategy,
+    /// The pre-flight check found the program can't run on this host
+    Elf(ElfError),
+    /// `dlopen` failed to load a `MemFdLibrary`, with the message of `dlerror`
+    Dlopen(String),
+    /// `dlsym` failed to find a symbol in a `MemFdLibrary`, with the message of `dlerror`
+    Dlsym(String),
+    /// Mapping the program for `LaunchStrategy::Userland` failed, `ENOEXEC` if it isn't a
+    /// static program for this architecture
+    Userland(i32),
+    /// Moving the program into a new process group or session failed
+    ProcessGroup(i32),
+    /// Setting the signal the program gets when its parent dies failed
+    ParentDeathSignal(i32),
+}
+
+impl MemFdExecError {
+    /// Get the `MemFdExecError` stored in an `io::Error` returned by this crate, if any.
+    pub fn from_io(err: &Error) -> Option<&MemFdExecError> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    /// The underlying `errno`, if the failure came from a system call.
+    pub fn errno(&self) -> Option<i32> {
+        match *self {
+            MemFdExecError::MemfdCreate(errno)
+            | MemFdExecError::WriteImage(errno)
+            | MemFdExecError::Seal(errno)
+            | MemFdExecError::Fexecve(errno)
+            | MemFdExecError::FallbackTmpfile { errno, .. }
+            | MemFdExecError::Chdir(errno)
+            | MemFdExecError::Dup2(errno)
+            | MemFdExecError::Signals(errno)
+            | MemFdExecError::Fork(errno)
+            | MemFdExecError::Userland(errno)
+            | MemFdExecError::ProcessGroup(errno)
+            | MemFdExecError::ParentDeathSignal(errno) => Some(errno),
+            MemFdExecError::NulInArgument
+            | MemFdExecError::NoLaunchStrategy
+            | MemFdExecError::Elf(_)
+            | MemFdExecError::Dlopen(_)
+            | MemFdExecError::Dlsym(_) => None,
+        }
+    }
+
+    /// The `ErrorKind` of the `io::Error` this error converts into.
+    pub fn kind(&self) -> ErrorKind {
+        match (self.errno(), self) {
+            (Some(errno), _) => Error::from_raw_os_error(errno).kind(),
+            (None, MemFdExecError::Elf(ElfError::MissingInterpreter(_))) => ErrorKind::NotFound,
+            (None, MemFdExecError::Dlsym(_)) => ErrorKind::NotFound,
+            (None, MemFdExecError::Dlopen(_)) => ErrorKind::Other,
+            (None, _) => ErrorKind::InvalidInput,
+        }
+    }
+
+    fn stage(&self) -> u32 {
+        match *self {
+            MemFdExecError::MemfdCreate(_) => 1,
+            MemFdExecError::WriteImage(_) => 2,
+            MemFdExecError::Seal(_) => 3,
+            MemFdExecError::Fexecve(_) => 4,
+            MemFdExecError::FallbackTmpfile { .. } => 5,
+            MemFdExecError::Chdir(_) => 6,
+            MemFdExecError::Dup2(_) => 7,
+            MemFdExecError::Signals(_) => 8,
+            MemFdExecError::Fork(_) => 9,
+            MemFdExecError::NulInArgument => 10,
+            MemFdExecError::NoLaunchStrategy => 11,
+            MemFdExecError::Elf(_) => 12,
+            MemFdExecError::Dlopen(_) => 13,
+            MemFdExecError::Dlsym(_) => 14,
+            MemFdExecError::Userland(_) => 15,
+            MemFdExecError::ProcessGroup(_) => 16,
+            MemFdExecError::ParentDeathSignal(_) => 17,
+        }
+    }
+
+    /// Serialize the error so the child can send it to the parent over the CLOEXEC pipe. The
+    /// layout is the errno and the stage as big-endian integers, followed by any details
+    /// (the directory for `FallbackTmpfile`, the `ElfError` for `Elf`, the message for
+    /// `Dlopen` and `Dlsym`).
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8);
+        bytes.extend_from_slice(&self.errno().unwrap_or(0).to_be_bytes());
+        bytes.extend_from_slice(&self.stage().to_be_bytes());
+        match *self {
+            MemFdExecError::FallbackTmpfile { ref dir, .. } => {
+                bytes.extend_from_slice(dir.as_os_str().as_bytes())
+            }
+            MemFdExecError::Elf(ref err) => bytes.extend_from_slice(&err.encode()),
+            MemFdExecError::Dlopen(ref msg) | MemFdExecError::Dlsym(ref msg) => {
+                bytes.extend_from_slice(msg.as_bytes())
+            }
+            _ => {}
+        }
+        bytes
+    }
+
+    /// The inverse of `encode`.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let (header, detail) = bytes.split_at(8);
+        let errno = i32::from_be_bytes(header[..4].try_into().unwrap());
+        let stage = u32::from_be_bytes(header[4..].try_into().unwrap());
+        Some(match stage {
+            1 => MemFdExecError::MemfdCreate(errno),
+            2 => MemFdExecError::WriteImage(errno),
+            3 => MemFdExecError::Seal(errno),
+            4 => MemFdExecError::Fexecve(errno),
+            5 => MemFdExecError::FallbackTmpfile {
+                dir: PathBuf::from(OsStr::from_bytes(detail)),
+                errno,
+            },
+            6 => MemFdExecError::Chdir(errno),
+            7 => MemFdExecError::Dup2(errno),
+            8 => MemFdExecError::Signals(errno),
+            9 => MemFdExecError::Fork(errno),
+            10 => MemFdExecError::NulInArgument,
+            11 => MemFdExecError::NoLaunchStrategy,
+            12 => MemFdExecError::Elf(ElfError::decode(detail)?),
+            13 => MemFdExecError::Dlopen(String::from_utf8_lossy(detail).into_owned()),
+            14 => MemFdExecError::Dlsym(String::from_utf8_lossy(detail).into_owned()),
+            15 => MemFdExecError::Userland(errno),
+            16 => MemFdExecError::ProcessGroup(errno),
+            17 => MemFdExecError::ParentDeathSignal(errno),
+            _ => return None,
+        })
+    }
+}
+
+/// Get an errno out of an `io::Error`. Errors we made up ourselves (like a short write) don't
+/// have one, so pick the closest match.
+pub(crate) fn errno_of(err: &Error) -> i32 {
+    if let Some(errno) = MemFdExecError::from_io(err).and_then(MemFdExecError::errno) {
+        return errno;
+    }
+    err.raw_os_error().unwrap_or(match err.kind() {
+        ErrorKind::StorageFull => libc::ENOSPC,
+        ErrorKind::InvalidInput => libc::EINVAL,
+        _ => libc::EIO,
+    })
+}
+
+impl Display for MemFdExecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let os = |errno: i32| Error::from_raw_os_error(errno);
+        match *self {
+            MemFdExecError::MemfdCreate(errno) => {
+                write!(f, "failed to create memfd: {}", os(errno))
+            }
+            MemFdExecError::WriteImage(errno) => {
+                write!(f, "failed to write program image: {}", os(errno))
+            }
+            MemFdExecError::Seal(errno) => write!(f, "failed to seal memfd: {}", os(errno)),
+            MemFdExecError::Fexecve(errno) => write!(f, "failed to exec memfd: {}", os(errno)),
+            MemFdExecError::FallbackTmpfile { ref dir, errno } => write!(
+                f,
+                "failed to exec tmpfile in {}: {}",
+                dir.display(),
+                os(errno)
+            ),
+            MemFdExecError::Chdir(errno) => {
+                write!(f, "failed to change working directory: {}", os(errno))
+            }
+            MemFdExecError::Dup2(errno) => write!(f, "failed to redirect stdio: {}", os(errno)),
+            MemFdExecError::Signals(errno) => {
+                write!(f, "failed to reset signal handling: {}", os(errno))
+            }
+            MemFdExecError::Fork(errno) => write!(f, "failed to fork: {}", os(errno)),
+            MemFdExecError::NulInArgument => write!(f, "nul byte found in provided data"),
+            MemFdExecError::NoLaunchStrategy => write!(f, "no launch strategy left to try"),
+            MemFdExecError::Elf(ref err) => write!(f, "cannot run program: {err}"),
+            MemFdExecError::Dlopen(ref msg) => write!(f, "failed to load library: {msg}"),
+            MemFdExecError::Dlsym(ref msg) => write!(f, "failed to find symbol: {msg}"),
+            MemFdExecError::Userland(errno) => {
+                write!(f, "failed to map program in userland: {}", os(errno))
+            }
+            MemFdExecError::ProcessGroup(errno) => {
+                write!(f, "failed to set process group: {}", os(errno))
+            }
+            MemFdExecError::ParentDeathSignal(errno) => {
+                write!(f, "failed to set parent death signal: {}", os(errno))
+            }
+        }
+    }
+}
+
+impl StdError for MemFdExecError {}
+
+impl From<MemFdExecError> for Error {
+    fn from(err: MemFdExecError) -> Self {
+        Error::new(err.kind(), err)
+    }
+}
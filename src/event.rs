@@ -0,0 +1,101 @@
+//! Diagnostic events emitted while launching an in-memory executable, and the hook used to
+//! receive them.
+
+use std::{
+    ffi::OsStr,
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    os::unix::prelude::OsStrExt,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use crate::error::MemFdExecError;
+
+/// Something noteworthy that happened while launching a program, usually a step that failed
+/// and was recovered from by trying something else. Events are silent unless a hook is set
+/// with `MemFdExecutable::on_event` or the `log` feature is enabled, and they are never written
+/// to the stdio of the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LaunchEvent {
+    /// Executing from a memfd was disabled with `NO_MEMFDEXEC=1`, so only the launch
+    /// strategies which write the program to disk are tried
+    MemfdDisabled,
+    /// A step failed, and the next way of launching the program will be tried
+    Failed(MemFdExecError),
+    /// The program is about to be written to a tmpfile in `dir`
+    TryingTmpfile {
+        /// The directory the tmpfile will be created in
+        dir: PathBuf,
+    },
+}
+
+impl LaunchEvent {
+    /// Serialize the event as a tag followed by its payload.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let (tag, payload): (u32, Vec<u8>) = match *self {
+            LaunchEvent::MemfdDisabled => (1, Vec::new()),
+            LaunchEvent::Failed(ref err) => (2, err.encode()),
+            LaunchEvent::TryingTmpfile { ref dir } => (3, dir.as_os_str().as_bytes().to_vec()),
+        };
+        let mut bytes = tag.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    /// The inverse of `encode`.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let (tag, payload) = bytes.split_at(4);
+        Some(match u32::from_be_bytes(tag.try_into().unwrap()) {
+            1 => LaunchEvent::MemfdDisabled,
+            2 => LaunchEvent::Failed(MemFdExecError::decode(payload)?),
+            3 => LaunchEvent::TryingTmpfile {
+                dir: PathBuf::from(OsStr::from_bytes(payload)),
+            },
+            _ => return None,
+        })
+    }
+}
+
+impl Display for LaunchEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match *self {
+            LaunchEvent::MemfdDisabled => write!(f, "memfd-exec is disabled"),
+            LaunchEvent::Failed(ref err) => write!(f, "{err}"),
+            LaunchEvent::TryingTmpfile { ref dir } => {
+                write!(f, "trying tmpfile in {}", dir.display())
+            }
+        }
+    }
+}
+
+/// A user-supplied callback receiving `LaunchEvent`s.
+#[derive(Clone)]
+pub(crate) struct EventHook(Arc<dyn Fn(&LaunchEvent) + Send + Sync>);
+
+impl EventHook {
+    pub(crate) fn new<F: Fn(&LaunchEvent) + Send + Sync + 'static>(hook: F) -> Self {
+        Self(Arc::new(hook))
+    }
+}
+
+impl Debug for EventHook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("EventHook").finish_non_exhaustive()
+    }
+}
+
+/// Deliver an event to the hook if there is one, or to the `log` crate if that feature is
+/// enabled. Otherwise the event is dropped.
+pub(crate) fn dispatch(hook: Option<&EventHook>, event: &LaunchEvent) {
+    match hook {
+        Some(hook) => (hook.0)(event),
+        #[cfg(feature = "log")]
+        None => log::debug!("{event}"),
+        #[cfg(not(feature = "log"))]
+        None => {}
+    }
+}
@@ -7,35 +7,43 @@
 
 use std::{
     env,
-    process::exit,
-    time::Duration,
     mem::MaybeUninit,
     collections::BTreeMap,
-    thread::{spawn, sleep},
-    io::{Error, ErrorKind, Result},
+    io::{Error, ErrorKind, Read, Result},
     ffi::{CStr, CString, OsStr, OsString},
-    path::{Path, PathBuf}, process, ptr::null_mut,
-    fs::{self, create_dir_all, set_permissions, File, Permissions},
-    os::{unix::{fs::PermissionsExt, prelude::{OsStrExt, OsStringExt}}, fd::{AsFd, AsRawFd}},
+    path::{Path, PathBuf}, ptr::{null, null_mut},
+    fs::{self, File},
+    os::{unix::{fs::{OpenOptionsExt, PermissionsExt}, prelude::{OsStrExt, OsStringExt}}, fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd}},
 };
 
-use libc::{close, pid_t, sigemptyset, signal};
+use libc::{c_int, pid_t, sigemptyset, signal};
 use nix::{
-    errno::Errno,
-    sys::{memfd::{memfd_create, MFdFlags}, wait::waitpid},
-    unistd::{access, fexecve, execve, write, fork, setsid, AccessFlags, ForkResult},
+    sys::memfd::MFdFlags,
+    unistd::{access, fexecve, execve, AccessFlags},
 };
 
 use crate::{
     anon_pipe::anon_pipe,
+    binfmt::{needs_interpreter, HEADER_LEN},
     child::Child,
     command_env::CommandEnv,
     cvt::{cvt, cvt_nz, cvt_r},
+    error::{errno_of, MemFdExecError},
+    event::{dispatch, EventHook, LaunchEvent},
+    code::CodeSource,
+    elf::{ElfError, ElfInfo},
+    image::{create_exec_memfd, write_prog, MemFdImage},
     output::Output,
     process::{ExitStatus, Process},
+    report::{Report, Reporter},
     stdio::{ChildPipes, Stdio, StdioPipes},
+    strategy::{LaunchInfo, LaunchStrategy},
+    tmpfile::{TmpFile, TmpFileOptions},
+    userland,
 };
 
+type LaunchResult<T = ()> = std::result::Result<T, MemFdExecError>;
+
 /// This is the main struct used to create an in-memory only executable. Wherever possible, it
 /// is intended to be a drop-in replacement for the standard library's `process::Command` struct.
 ///
@@ -72,12 +80,13 @@ use crate::{
 /// // Then, we can wait for the program to exit.
 /// cmd.wait();
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MemFdExecutable<'a> {
     /// The contents of the ELF executable to run. This content can be included in the file
     /// using the `include_bytes!()` macro, or you can do fancy things like read it in from
-    /// a socket.
-    code: &'a [u8],
+    /// a socket. It can be borrowed, owned, shared or a `MemFdImage` which has already been
+    /// written to a sealed memfd and is shared between many executables.
+    code: CodeSource<'a>,
     /// The name of the program
     name: String,
     /// The name of the program, this value is the argv\[0\] argument to the binary when
@@ -100,9 +109,193 @@ pub struct MemFdExecutable<'a> {
     pub stderr: Option<Stdio>,
     /// Holdover from Command, whether there was a NUL in the arguments or not
     saw_nul: bool,
+    /// Receives diagnostic events while launching the program
+    hook: Option<EventHook>,
+    /// The ways of launching the program to try in order, `None` for the defaults
+    strategies: Option<Vec<LaunchStrategy>>,
+    /// How the program is named on disk when it is written to a tmpfile
+    tmpfile: TmpFileOptions,
+    /// Whether the memfd is closed on exec, `None` to detect it
+    memfd_cloexec: Option<bool>,
+    /// The interpreter the program is a script for
+    interpreter: Option<CString>,
+    /// The arguments passed to the interpreter before the path of the script
+    interpreter_args: Vec<CString>,
+    /// Whether to check the ELF header of the program before forking
+    check_elf: bool,
+    /// The qemu-user emulator to run foreign architecture programs with
+    emulator: Option<CodeSource<'a>>,
+    /// The program loader (`ld-linux`) to run dynamically linked programs with
+    loader: Option<CodeSource<'a>>,
+    /// The shared libraries preloaded by the loader, by `DT_SONAME`
+    libraries: Vec<(CString, CodeSource<'a>)>,
+    /// The shared libraries added to `LD_PRELOAD`
+    preloads: Vec<(CString, CodeSource<'a>)>,
+    /// The process group to move the program into, 0 for a new one led by the program
+    pgroup: Option<pid_t>,
+    /// Whether to start the program in a new session
+    setsid: bool,
+    /// Whether the child is killed when its `Child` is dropped
+    kill_on_drop: bool,
+    /// The signal the program gets when its parent dies
+    pdeathsig: Option<c_int>,
+}
+
+/// What the parent decided about launching the program before forking, so the child doesn't
+/// have to look at the environment or the filesystem.
+struct LaunchPlan {
+    strategies: Vec<LaunchStrategy>,
+    /// Whether the descriptor the program is executed from is closed on exec
+    cloexec: bool,
+    /// The emulator to run the program with, if it is built for another architecture
+    emulator: Option<Emulator>,
+    /// The loader and libraries to run a dynamically linked program with
+    bundle: Option<Bundle>,
+    /// The memfds of the `preload`ed libraries, kept open in the program
+    preloads: Vec<OwnedFd>,
+}
+
+impl LaunchPlan {
+    /// Prepend the preloaded libraries to `LD_PRELOAD` in `envp`, keeping any value it
+    /// already has.
+    fn preload_env(&self, envp: &mut Vec<CString>) {
+        if self.preloads.is_empty() {
+            return;
+        }
+        let mut preload = self
+            .preloads
+            .iter()
+            .map(|fd| format!("/proc/self/fd/{}", fd.as_raw_fd()).into_bytes())
+            .collect::<Vec<_>>()
+            .join(&b':');
+        let existing = envp
+            .iter()
+            .position(|var| var.as_bytes().starts_with(b"LD_PRELOAD="));
+        if let Some(value) = existing.map(|i| envp.remove(i)) {
+            let value = &value.as_bytes()[b"LD_PRELOAD=".len()..];
+            if !value.is_empty() {
+                preload.push(b':');
+                preload.extend_from_slice(value);
+            }
+        }
+        envp.push(CString::new([b"LD_PRELOAD=".as_slice(), &preload].concat()).unwrap());
+    }
+}
+
+/// An emulator loaded into a memfd before forking.
+struct Emulator {
+    fd: OwnedFd,
+    /// The argv\[0\] of the emulator, like `qemu-aarch64`
+    name: CString,
+    /// Whether the emulator is closed on exec, which it can't be if the kernel runs it
+    /// through an interpreter
+    cloexec: bool,
+}
+
+impl Emulator {
+    fn load(code: &CodeSource, info: &ElfInfo) -> LaunchResult<Self> {
+        let name = CString::new(format!("qemu-{}", info.machine_name())).unwrap();
+        let (fd, header) = load_memfd(&name, code)?;
+        Ok(Self {
+            fd,
+            name,
+            cloexec: !needs_interpreter(&header),
+        })
+    }
+}
+
+/// The program loader and the shared libraries of a bundle, loaded before forking.
+struct Bundle {
+    /// The argv\[0\] of the loader
+    name: CString,
+    loader: Loader,
+    /// Kept open in the program so the loader can map them
+    libraries: Vec<OwnedFd>,
+    /// The `--preload` argument of the loader: the paths of the libraries
+    preload: CString,
+}
+
+enum Loader {
+    /// The bundled loader
+    Memfd(OwnedFd),
+    /// The path of the loader the program names in `PT_INTERP`
+    Path(CString),
+}
+
+impl Loader {
+    fn path(&self) -> CString {
+        match *self {
+            Loader::Memfd(ref fd) => {
+                CString::new(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap()
+            }
+            Loader::Path(ref path) => path.clone(),
+        }
+    }
+}
+
+impl Bundle {
+    fn load(
+        loader: Option<&CodeSource>,
+        libraries: &[(CString, CodeSource)],
+        info: &ElfInfo,
+    ) -> LaunchResult<Self> {
+        let path = info.interpreter.as_deref().unwrap_or(Path::new("ld.so"));
+        let name = CString::new(path.file_name().unwrap_or_default().as_bytes()).unwrap();
+        let loader = match loader {
+            Some(code) => Loader::Memfd(load_memfd(&name, code)?.0),
+            None => Loader::Path(CString::new(path.as_os_str().as_bytes()).unwrap()),
+        };
+        let libraries = libraries
+            .iter()
+            .map(|(name, code)| Ok(load_memfd(name, code)?.0))
+            .collect::<LaunchResult<Vec<_>>>()?;
+        let preload = libraries
+            .iter()
+            .map(|fd| format!("/proc/self/fd/{}", fd.as_raw_fd()))
+            .collect::<Vec<_>>()
+            .join(":");
+        Ok(Self {
+            name,
+            loader,
+            libraries,
+            preload: CString::new(preload).unwrap(),
+        })
+    }
+
+    /// The arguments of the loader to execute the program at `path` with `argv`.
+    fn argv<'b>(&'b self, path: &'b CStr, argv: &[&'b CStr]) -> Vec<&'b CStr> {
+        let mut loader_argv = vec![self.name.as_c_str(), c"--argv0", argv[0]];
+        if !self.libraries.is_empty() {
+            loader_argv.extend([c"--preload", self.preload.as_c_str()]);
+        }
+        loader_argv.push(path);
+        loader_argv.extend(argv.iter().skip(1).copied());
+        loader_argv
+    }
+}
+
+/// Put `code` in a memfd named `name` (or share the one of an image), returning it and the
+/// start of the code.
+fn load_memfd(name: &CStr, code: &CodeSource) -> LaunchResult<(OwnedFd, Vec<u8>)> {
+    match code.as_image() {
+        Some(image) => {
+            let fd = image
+                .as_fd()
+                .try_clone_to_owned()
+                .map_err(|e| MemFdExecError::MemfdCreate(errno_of(&e)))?;
+            Ok((fd, image.read_at(0, HEADER_LEN)))
+        }
+        None => {
+            let code = code.as_bytes().unwrap_or_default();
+            let (fd, _) = create_exec_memfd(name, MFdFlags::MFD_CLOEXEC)
+                .map_err(|errno| MemFdExecError::MemfdCreate(errno as i32))?;
+            write_prog(&fd, code).map_err(|e| MemFdExecError::WriteImage(errno_of(&e)))?;
+            Ok((fd, code[..code.len().min(HEADER_LEN)].to_vec()))
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Argv(Vec<CString>);
 
 unsafe impl Send for Argv {}
@@ -134,43 +327,45 @@ fn construct_envp(env: BTreeMap<OsString, OsString>, saw_nul: &mut bool) -> Vec<
     result
 }
 
-fn create_and_open_file(path: &Path) -> Result<(File, i32)> {
-    let path_dir = path.parent().unwrap();
-    create_dir_all(path_dir)?;
-    set_permissions(path_dir, Permissions::from_mode(0o700))?;
-    let file = fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(path)?;
-    let fd_raw = file.as_raw_fd();
-    set_permissions(path, Permissions::from_mode(0o700))?;
-    Ok((file, fd_raw))
+fn do_fexecve<Fd: AsFd>(fd: &Fd, argv: &Vec<&CStr>, envp: &Vec<&CStr>) -> Result<()> {
+    fexecve(fd.as_fd(), argv, envp)?;
+    Ok(())
 }
 
-fn do_fexecve(fd: i32, argv: &Vec<&CStr>, envp: &Vec<&CStr>) -> Result<()> {
-    use std::os::unix::io::BorrowedFd;
-    let borrowed_fd = unsafe { BorrowedFd::borrow_raw(fd) };
-    let res = fexecve(borrowed_fd, argv, envp);
-    if res.is_err() {
-        // If we failed to exec, we need to close the memfd
-        // so that the child process doesn't leak it
-        unsafe { close(fd) };
-        return Err(Error::new(ErrorKind::PermissionDenied, res.err().unwrap()));
-    }
-    Ok(())
+unsafe fn set_cloexec(fd: i32, cloexec: bool) -> Result<()> {
+    let flags = cvt(libc::fcntl(fd, libc::F_GETFD))?;
+    let new = if cloexec {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    cvt(libc::fcntl(fd, libc::F_SETFD, new)).map(drop)
 }
 
-fn do_execve(path: &str, argv: &Vec<&CStr>, envp: &Vec<&CStr>) -> Result<()> {
-    let res = execve(&CString::new(path).unwrap(), argv, envp);
-    if res.is_err() {
-        return Err(Error::new(ErrorKind::PermissionDenied, res.err().unwrap()));
-    }
+/// Like `fexecve`, but calls `execveat` with `AT_EMPTY_PATH` directly instead of leaving the
+/// choice to libc, which may go through `/proc/self/fd` instead.
+fn do_execveat(fd: BorrowedFd, argv: &[&CStr], envp: &[&CStr]) -> Result<()> {
+    let argv = argv.iter().map(|s| s.as_ptr()).chain([null()]).collect::<Vec<_>>();
+    let envp = envp.iter().map(|s| s.as_ptr()).chain([null()]).collect::<Vec<_>>();
+    cvt(unsafe {
+        libc::syscall(
+            libc::SYS_execveat,
+            fd.as_raw_fd(),
+            c"".as_ptr(),
+            argv.as_ptr(),
+            envp.as_ptr(),
+            libc::AT_EMPTY_PATH,
+        )
+    })
+    .map(drop)
+}
+
+fn do_execve(path: &Path, argv: &Vec<&CStr>, envp: &Vec<&CStr>) -> Result<()> {
+    execve(&CString::new(path.as_os_str().as_bytes())?, argv, envp)?;
     Ok(())
 }
 
-fn is_exe(path: &Path) -> bool {
+pub(crate) fn is_exe(path: &Path) -> bool {
     if let Ok(metadata) = fs::metadata(path) {
         return metadata.is_file()
             && metadata.permissions().mode() & 0o111 != 0
@@ -179,17 +374,12 @@ fn is_exe(path: &Path) -> bool {
     false
 }
 
-fn try_setsid() {
-    if let Err(err) = setsid() {
-        eprintln!("Failed to call setsid: {err}");
-        exit(1)
-    }
-}
-
 impl<'a> MemFdExecutable<'a> {
     /// Create a new MemFdExecutable with the given name and code. The name is the name of the
     /// program, and is used as the argv\[0\] argument to the program. The code is the binary
-    /// code to execute (usually, the entire contents of an ELF file).
+    /// code to execute (usually, the entire contents of an ELF file). It can be anything that
+    /// converts into a `CodeSource`: a borrowed slice, an owned `Vec<u8>`, an `Arc<[u8]>` or a
+    /// `MemFdImage`.
     ///
     /// # Examples
     ///
@@ -209,7 +399,105 @@ impl<'a> MemFdExecutable<'a> {
     ///     .expect("failed to execute process");
     /// ```
     ///
-    pub fn new<S: AsRef<OsStr>>(name: S, code: &'a [u8]) -> Self {
+    ///
+    /// Owned code makes the executable `'static`, so it can be stored or sent to another
+    /// thread, and clones of it share the code instead of copying it:
+    ///
+    /// ```no_run
+    /// use std::{fs::read, thread::spawn};
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let ls = MemFdExecutable::new("ls", read("/bin/ls").unwrap());
+    /// let mut ls_home = ls.clone();
+    ///
+    /// spawn(move || ls_home.arg("/home").status().expect("failed to run ls"));
+    /// ```
+    pub fn new<S: AsRef<OsStr>, C: Into<CodeSource<'a>>>(name: S, code: C) -> Self {
+        Self::with_code(name, code.into())
+    }
+
+    /// Create a new MemFdExecutable with the given name, running the code stored in a
+    /// `MemFdImage`. The image is only written to memory once, so this is the way to go
+    /// when the same program is spawned over and over again: each child only has to exec
+    /// the inherited memfd.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::{MemFdExecutable, MemFdImage};
+    ///
+    /// let image = MemFdImage::new("ls", &read("/bin/ls").unwrap()).unwrap();
+    ///
+    /// let children = (0..100)
+    ///     .map(|_| MemFdExecutable::from_image("ls", &image).spawn())
+    ///     .collect::<Result<Vec<_>, _>>()
+    ///     .expect("failed to spawn ls");
+    /// ```
+    pub fn from_image<S: AsRef<OsStr>>(name: S, image: &MemFdImage) -> Self {
+        Self::with_code(name, image.into())
+    }
+
+    /// Create a new MemFdExecutable with the given name, streaming the code from `reader`
+    /// into a sealed memfd. Unlike reading everything into a `Vec<u8>` first, only one copy of
+    /// the code is ever held in memory. See `MemFdImage::from_reader`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::net::TcpStream;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let stream = TcpStream::connect("127.0.0.1:4444").unwrap();
+    /// MemFdExecutable::from_reader("payload", stream)
+    ///     .expect("failed to receive payload")
+    ///     .status()
+    ///     .expect("failed to run payload");
+    /// ```
+    pub fn from_reader<S: AsRef<OsStr>, R: Read>(name: S, reader: R) -> Result<Self> {
+        let image = MemFdImage::from_reader(name.as_ref(), reader)?;
+        Ok(Self::with_code(name, image.into()))
+    }
+
+    /// Create a new MemFdExecutable running a script, like a shell, awk or Python script
+    /// embedded in your program. The script is placed in a memfd and run by the interpreter
+    /// named on its `#!` line, or by `/bin/sh` if it has none. Use `interpreter` to pick the
+    /// interpreter yourself.
+    ///
+    /// Arguments, environment variables and stdio apply as usual, they are just handed to
+    /// the interpreter.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use memfd_exec::{MemFdExecutable, Stdio};
+    ///
+    /// let output = MemFdExecutable::script("greet", b"echo \"hello $1\"")
+    ///     .arg("world")
+    ///     .stdout(Stdio::piped())
+    ///     .output()
+    ///     .expect("failed to run script");
+    /// assert_eq!(output.stdout, b"hello world\n");
+    ///
+    /// MemFdExecutable::script("count", b"END { print NR }")
+    ///     .interpreter("/usr/bin/awk")
+    ///     .interpreter_args(["-f"])
+    ///     .arg("/etc/passwd")
+    ///     .status()
+    ///     .expect("failed to run awk");
+    /// ```
+    pub fn script<S: AsRef<OsStr>, C: Into<CodeSource<'a>>>(name: S, code: C) -> Self {
+        let mut script = Self::with_code(name, code.into());
+        if !script.program_header().starts_with(b"#!") {
+            script.interpreter("/bin/sh");
+        }
+        script
+    }
+
+    fn with_code<S: AsRef<OsStr>>(name: S, code: CodeSource<'a>) -> Self {
         let mut saw_nul = false;
         let name_cstr = os2c(name.as_ref(), &mut saw_nul);
         Self {
@@ -224,6 +512,21 @@ impl<'a> MemFdExecutable<'a> {
             stdout: None,
             stderr: None,
             saw_nul,
+            hook: None,
+            strategies: None,
+            tmpfile: TmpFileOptions::default(),
+            memfd_cloexec: None,
+            interpreter: None,
+            interpreter_args: Vec::new(),
+            check_elf: false,
+            emulator: None,
+            loader: None,
+            libraries: Vec::new(),
+            preloads: Vec::new(),
+            pgroup: None,
+            setsid: false,
+            kill_on_drop: false,
+            pdeathsig: None,
         }
     }
 
@@ -375,21 +678,291 @@ impl<'a> MemFdExecutable<'a> {
         self
     }
 
+    /// Set a callback receiving diagnostic events while the program is launched, for example
+    /// when executing from a memfd fails and a tmpfile is tried instead. By default these
+    /// events are silent (or sent to the `log` crate with the `log` feature). They are never
+    /// written to the stdio of the program.
+    ///
+    /// When the program is spawned, the events are collected from the child and the callback
+    /// runs in the parent before `spawn` returns.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let ls = MemFdExecutable::new("ls", read("/bin/ls").unwrap())
+    ///     .on_event(|event| eprintln!("launching ls: {event}"))
+    ///     .spawn()
+    ///     .expect("failed to spawn ls");
+    /// ```
+    pub fn on_event<F: Fn(&LaunchEvent) + Send + Sync + 'static>(&mut self, hook: F) -> &mut Self {
+        self.hook = Some(EventHook::new(hook));
+        self
+    }
+
+    /// Set the ways of launching the program, tried in order until one of them works. The
+    /// default is `LaunchStrategy::defaults()`: a memfd, then a tmpfile on disk. Restricting
+    /// the chain to in-memory strategies guarantees the program is never written to disk,
+    /// even when `NO_MEMFDEXEC=1` is set (the launch fails instead). See `LaunchStrategy`.
+    pub fn strategies<I: IntoIterator<Item = LaunchStrategy>>(&mut self, strategies: I) -> &mut Self {
+        self.strategies = Some(strategies.into_iter().collect());
+        self
+    }
+
+    /// Set how tmpfiles holding the program are named, relative to the directory they are
+    /// created in. `{uid}`, `{pid}`, `{rand}` and `{name}` are replaced with the user id, the
+    /// process id of the program, eight random hex digits and the program name.
+    /// Missing directories are created, and every directory created this way is removed
+    /// together with the tmpfile. The default is `mfd{uid}{pid}-{rand}/{name}`.
+    ///
+    /// A tmpfile is removed as soon as the program has been executed from it, or when the
+    /// launch fails: by the parent after `spawn`, or by a small detached process after
+    /// `exec`. `LaunchStrategy::OTmpFile` needs no cleanup at all.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::{LaunchStrategy, MemFdExecutable};
+    ///
+    /// MemFdExecutable::new("ls", read("/bin/ls").unwrap())
+    ///     .strategies([LaunchStrategy::TmpFile(vec!["/var/tmp".into()])])
+    ///     .tmpfile_name("myapp-{rand}-{name}")
+    ///     .tmpfile_mode(0o500)
+    ///     .status()
+    ///     .expect("failed to run ls");
+    /// ```
+    pub fn tmpfile_name<S: Into<String>>(&mut self, template: S) -> &mut Self {
+        self.tmpfile.template = template.into();
+        self
+    }
+
+    /// Set the permissions tmpfiles holding the program are created with. The default is
+    /// `0o700`. The mode must allow the owner to execute the file.
+    pub fn tmpfile_mode(&mut self, mode: u32) -> &mut Self {
+        self.tmpfile.mode = mode;
+        self
+    }
+
+    /// Run the program as a script for the interpreter at the path `interpreter` (`PATH` is
+    /// not searched). The interpreter is executed with the path of the script
+    /// (`/proc/self/fd/N`) as its first argument, followed by the arguments of the program.
+    /// The script doesn't need a `#!` line and is never written to disk by the memfd
+    /// strategies. See `script`.
+    pub fn interpreter<S: AsRef<OsStr>>(&mut self, interpreter: S) -> &mut Self {
+        self.interpreter = Some(os2c(interpreter.as_ref(), &mut self.saw_nul));
+        self
+    }
+
+    /// Add arguments passed to the interpreter before the path of the script, like `-f` for
+    /// awk. Only used when an `interpreter` is set, which can be before or after this call.
+    pub fn interpreter_args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            let arg = os2c(arg.as_ref(), &mut self.saw_nul);
+            self.interpreter_args.push(arg);
+        }
+        self
+    }
+
+    /// Set whether the memfd (or `O_TMPFILE`) the program is executed from is closed on exec.
+    /// Closing it keeps it out of the file descriptor table of the program, but a program the
+    /// kernel runs through an interpreter can't be executed that way, because the interpreter
+    /// has to reopen it. That is the case for `#!` scripts, and for programs handled by
+    /// `binfmt_misc` (for example a foreign architecture binary run by qemu-user, which is also
+    /// what happens when we run under qemu-user ourselves).
+    ///
+    /// By default the descriptor is closed unless the program starts with `#!` or matches an
+    /// enabled `binfmt_misc` entry. It is always kept open when an `interpreter` is set.
+    /// Override this when the detection gets it wrong, for example when the program is
+    /// started by an emulator which isn't registered with `binfmt_misc`.
+    /// `LaunchStrategy::MemfdNoCloexec` always keeps the memfd open.
+    pub fn memfd_cloexec(&mut self, cloexec: bool) -> &mut Self {
+        self.memfd_cloexec = Some(cloexec);
+        self
+    }
+
+    /// Check the ELF header of the program before forking, so a program which can't run on
+    /// this host fails with a `MemFdExecError::Elf` describing why (like "aarch64 binary on
+    /// x86_64 host" or "truncated ELF") instead of an `ENOEXEC` from deep inside the child.
+    /// The program must be an executable or a PIE for the architecture of the host (or one
+    /// a `binfmt_misc` emulator is registered for), and its program interpreter must exist.
+    /// Scripts and programs run by an `interpreter` are not checked. Off by default.
+    pub fn check_elf(&mut self, check: bool) -> &mut Self {
+        self.check_elf = check;
+        self
+    }
+
+    /// Parse the ELF header of the program.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::read;
+    ///
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let ls = MemFdExecutable::new("ls", read("/bin/ls").unwrap());
+    /// let info = ls.elf_info().expect("ls is an ELF file");
+    /// println!("{} needs {:?}", info.machine_name(), info.interpreter);
+    /// ```
+    pub fn elf_info(&self) -> std::result::Result<ElfInfo, ElfError> {
+        match self.code.as_image() {
+            Some(image) => ElfInfo::parse_with(|offset, len| {
+                Some(image.read_at(offset, len)).filter(|bytes| bytes.len() == len)
+            }),
+            None => ElfInfo::parse(self.code.as_bytes().unwrap_or_default()),
+        }
+    }
+
+    /// Run the program with an embedded qemu-user emulator (like a static `qemu-aarch64`) when
+    /// its ELF header says it is built for another architecture than this host. The emulator
+    /// is executed from a memfd of its own and gets the program as `/proc/self/fd/N`, followed
+    /// by its arguments. The argv\[0\] of the program is passed with `-0` and the environment
+    /// is inherited by the emulator, so options like `QEMU_LD_PREFIX` (where to find the
+    /// libraries of a dynamically linked program) can be set with `env`. Programs for this
+    /// host are executed directly.
+    ///
+    /// # Examples
+    ///
+    /// ```no_compile
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let qemu = include_bytes!("qemu-aarch64-static");
+    /// let status = MemFdExecutable::new("tests", include_bytes!("tests-aarch64"))
+    ///     .emulator(qemu)
+    ///     .arg("--nocapture")
+    ///     .status()
+    ///     .expect("failed to run the aarch64 tests");
+    /// ```
+    pub fn emulator<C: Into<CodeSource<'a>>>(&mut self, code: C) -> &mut Self {
+        self.emulator = Some(code.into());
+        self
+    }
+
+    /// Run a dynamically linked program with this program loader (like
+    /// `ld-linux-x86-64.so.2`) from memory, instead of the one named by its `PT_INTERP`, so it
+    /// runs on hosts where that doesn't exist. The loader is executed from a memfd and maps
+    /// the program from `/proc/self/fd/N`, with the bundled `library`s preloaded. Needs a
+    /// loader which understands `--argv0` and `--preload` (glibc 2.33+ or musl). Statically
+    /// linked programs and scripts are executed as usual.
+    ///
+    /// # Examples
+    ///
+    /// ```no_compile
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let status = MemFdExecutable::new("app", include_bytes!("bundle/app"))
+    ///     .loader(include_bytes!("bundle/ld-linux-x86-64.so.2"))
+    ///     .library("libc.so.6", include_bytes!("bundle/libc.so.6"))
+    ///     .library("libssl.so.3", include_bytes!("bundle/libssl.so.3"))
+    ///     .status()
+    ///     .expect("failed to run the bundled app");
+    /// ```
+    pub fn loader<C: Into<CodeSource<'a>>>(&mut self, code: C) -> &mut Self {
+        self.loader = Some(code.into());
+        self
+    }
+
+    /// Bundle a shared library with a dynamically linked program, so it is loaded from memory
+    /// instead of from disk. The library is put in a memfd named `name` and preloaded by the
+    /// loader, which uses it for every `DT_NEEDED` entry matching the `DT_SONAME` of the
+    /// library, so `name` should be that soname (like `libssl.so.3`). Without a bundled
+    /// `loader`, the one named by the program's `PT_INTERP` is used from disk.
+    pub fn library<S: AsRef<OsStr>, C: Into<CodeSource<'a>>>(&mut self, name: S, code: C) -> &mut Self {
+        let name = os2c(name.as_ref(), &mut self.saw_nul);
+        self.libraries.push((name, code.into()));
+        self
+    }
+
+    /// Preload a shared object (like an instrumentation shim) into the program from memory.
+    /// The object is put in a memfd named `name` which stays open in the program, and its
+    /// `/proc/self/fd/N` path is put in front of `LD_PRELOAD`, ahead of any value set with
+    /// `env` or inherited from us. Nothing is written to disk. Programs the preload is
+    /// inherited by don't have the descriptor, and their loader skips it with a warning.
+    ///
+    /// # Examples
+    ///
+    /// ```no_compile
+    /// use memfd_exec::MemFdExecutable;
+    ///
+    /// let status = MemFdExecutable::new("tool", include_bytes!("tool"))
+    ///     .preload("trace.so", include_bytes!("trace.so"))
+    ///     .env("TRACE_OUTPUT", "/tmp/trace.log")
+    ///     .status()
+    ///     .expect("failed to run the instrumented tool");
+    /// ```
+    pub fn preload<S: AsRef<OsStr>, C: Into<CodeSource<'a>>>(&mut self, name: S, code: C) -> &mut Self {
+        let name = os2c(name.as_ref(), &mut self.saw_nul);
+        self.preloads.push((name, code.into()));
+        self
+    }
+
+    /// Move the program into the process group `pgroup` before it is executed, or into a new
+    /// group led by the program if `pgroup` is 0, like `CommandExt::process_group()`. The
+    /// whole group can then be signalled with `Child::signal_group()`, which takes any
+    /// helpers the program forks along with it.
+    pub fn process_group(&mut self, pgroup: i32) -> &mut Self {
+        self.pgroup = Some(pgroup);
+        self
+    }
+
+    /// Start the program in a new session (`setsid`), which also makes it the leader of a
+    /// new process group and detaches it from our controlling terminal. Can't be combined
+    /// with `process_group`: `spawn` fails with `InvalidInput` before forking if both are set.
+    pub fn setsid(&mut self, setsid: bool) -> &mut Self {
+        self.setsid = setsid;
+        self
+    }
+
+    /// Kill the child process with `SIGKILL` and reap it when its `Child` (or the
+    /// `AsyncChild` it was turned into) is dropped without having been waited for, so a
+    /// panicking supervisor doesn't leave it running. Off by default, like `Command`.
+    pub fn kill_on_drop(&mut self, kill_on_drop: bool) -> &mut Self {
+        self.kill_on_drop = kill_on_drop;
+        self
+    }
+
+    /// Have the kernel send `signal` (usually `libc::SIGKILL`) to the program when its
+    /// parent dies, with `PR_SET_PDEATHSIG`, so it can't outlive a supervisor which was
+    /// killed before it could clean up. If the parent already died before this was set up,
+    /// the program is sent the signal straight away.
+    ///
+    /// The kernel considers the *thread* which spawned the program its parent: spawning
+    /// from a thread which exits early sends the signal then.
+    pub fn parent_death_signal(&mut self, signal: c_int) -> &mut Self {
+        self.pdeathsig = Some(signal);
+        self
+    }
+
     /// Spawn the program as a child process. This is equivalent to `Command::spawn()`.
     pub fn spawn(&mut self) -> Result<Child> {
         let default = Stdio::Inherit;
         let needs_stdin = true;
-        const CLOEXEC_MSG_FOOTER: [u8; 4] = *b"NOEX";
 
-        let envp = self.capture_env();
+        let mut envp = self.capture_env();
 
         if self.saw_nul() {
-            // TODO: Need err?
+            return Err(MemFdExecError::NulInArgument.into());
         }
 
+        self.check_process_group()?;
+        self.preflight()?;
+
         let (ours, theirs) = self.setup_io(default, needs_stdin)?;
 
+        let plan = self.launch_plan(&Reporter::Direct(self.hook.as_ref()))?;
+        plan.preload_env(&mut envp);
+
         let (input, output) = anon_pipe()?;
+        let parent = unsafe { libc::getpid() };
 
         // Whatever happens after the fork is almost for sure going to touch or
         // look at the environment in one way or another (PATH in `execvp` or
@@ -402,46 +975,66 @@ impl<'a> MemFdExecutable<'a> {
         // forgets it to avoid unlocking it on a new thread, which would be invalid.
         // TODO: Yeah....I had to remove the env lock. Whoops! Don't multithread env with this
         // you insane person
-        let pid = unsafe { self.do_fork()? };
+        let pid = unsafe { self.do_fork() }
+            .map_err(|e| Error::from(MemFdExecError::Fork(errno_of(&e))))?;
 
         if pid == 0 {
             drop(input);
-            let Err(err) = (unsafe { self.do_exec(theirs, envp) }) else { unreachable!("..."); };
-            panic!("failed to exec: {}", err);
+            let reporter = Reporter::Pipe(&output);
+            let Err(err) = (unsafe { self.do_exec(theirs, envp, &plan, parent, &reporter) }) else { unreachable!("..."); };
+            // We want to be sure we *don't* run at_exit destructors or the parent's panic
+            // hook as we're being torn down regardless
+            reporter.error(err);
+            unsafe { libc::_exit(127) }
         }
 
         drop(output);
 
-        // Safety: We obtained the pidfd from calling `clone3` with
-        // `CLONE_PIDFD` so it's valid an otherwise unowned.
+        // Safety: The child is ours and hasn't been waited for, so the pidfd opened for it
+        // can't refer to another process.
         let mut p = unsafe { Process::new(pid) };
-        let mut bytes = [0; 8];
-
-        // loop to handle EINTR
-        loop {
-            match input.read(&mut bytes) {
-                Ok(0) => return Ok(Child::new(p, ours)),
-                Ok(8) => {
-                    let (errno, footer) = bytes.split_at(4);
-                    assert_eq!(
-                        CLOEXEC_MSG_FOOTER, footer,
-                        "Validation on the CLOEXEC pipe failed: {:?}",
-                        bytes
-                    );
-                    let errno = i32::from_be_bytes(errno.try_into().unwrap());
-                    assert!(p.wait().is_ok(), "wait() should either return Ok or panic");
-                    return Err(Error::from_raw_os_error(errno));
-                }
-                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-                Err(e) => {
-                    assert!(p.wait().is_ok(), "wait() should either return Ok or panic");
-                    panic!("the CLOEXEC pipe failed: {e:?}")
-                }
-                Ok(..) => {
-                    // pipe I/O up to PIPE_BUF bytes should be atomic
-                    assert!(p.wait().is_ok(), "wait() should either return Ok or panic");
-                    panic!("short read on the CLOEXEC pipe")
-                }
+        p.set_kill_on_drop(self.kill_on_drop);
+        let mut bytes = Vec::new();
+
+        // The child either execs, closing the pipe, or reports an error and exits, so we
+        // read until the pipe is closed (read_to_end handles EINTR)
+        if let Err(e) = input.read_to_end(&mut bytes) {
+            assert!(p.wait().is_ok(), "wait() should either return Ok or panic");
+            panic!("the CLOEXEC pipe failed: {e:?}")
+        }
+
+        let Some(reports) = Report::decode_all(&bytes) else {
+            assert!(p.wait().is_ok(), "wait() should either return Ok or panic");
+            panic!("Validation on the CLOEXEC pipe failed: {:?}", bytes)
+        };
+
+        let mut result = Ok(());
+        let mut launch = None;
+        for report in reports {
+            match report {
+                Report::Event(event) => dispatch(self.hook.as_ref(), &event),
+                Report::Error(err) => result = Err(err),
+                // The pipe is closed, so the program has been executed (or the child died)
+                // and the tmpfile isn't needed any more
+                Report::Cleanup(tmpfile) => tmpfile.remove(),
+                Report::Launch(info) => launch = Some(info),
+            }
+        }
+
+        // The child has been moved into its group by now, it does that before executing
+        let group = match self.pgroup {
+            _ if self.setsid => Some(pid),
+            Some(0) => Some(pid),
+            pgroup => pgroup,
+        };
+
+        match result {
+            Ok(()) => Ok(Child::new(p, ours)
+                .with_launch_info(launch)
+                .with_process_group(group)),
+            Err(err) => {
+                assert!(p.wait().is_ok(), "wait() should either return Ok or panic");
+                Err(err.into())
             }
         }
     }
@@ -507,9 +1100,11 @@ impl<'a> MemFdExecutable<'a> {
         cvt(libc::fork())
     }
 
-    fn capture_env(&mut self) -> Option<Vec<CString>> {
-        let maybe_env = self.env.capture_if_changed();
-        maybe_env.map(|env| construct_envp(env, &mut self.saw_nul))
+    /// The environment of the program: ours with the changes made by `env` and friends, or
+    /// only those after `env_clear`, like `Command`.
+    fn capture_env(&mut self) -> Vec<CString> {
+        let env = self.env.capture();
+        construct_envp(env, &mut self.saw_nul)
     }
 
     /// Execute the command as a new process, replacing the current process.
@@ -519,16 +1114,30 @@ impl<'a> MemFdExecutable<'a> {
     /// # Arguments
     /// * `default` - The default stdio to use if the child process does not specify.
     pub fn exec(&mut self, default: Stdio) -> Error {
-        let envp = self.capture_env();
+        let mut envp = self.capture_env();
 
         if self.saw_nul() {
-            return Error::new(ErrorKind::InvalidInput, "nul byte found in provided data");
+            return MemFdExecError::NulInArgument.into();
+        }
+
+        if let Err(err) = self.check_process_group() {
+            return err;
+        }
+
+        if let Err(err) = self.preflight() {
+            return err.into();
         }
 
         match self.setup_io(default, true) {
             Ok((_, theirs)) => unsafe {
-                let Err(e) = self.do_exec(theirs, envp) else { unreachable!("..."); };
-                e
+                let reporter = Reporter::Direct(self.hook.as_ref());
+                let plan = match self.launch_plan(&reporter) {
+                    Ok(plan) => plan,
+                    Err(e) => return e.into(),
+                };
+                plan.preload_env(&mut envp);
+                let Err(e) = self.do_exec(theirs, envp, &plan, libc::getppid(), &reporter) else { unreachable!("..."); };
+                e.into()
             },
             Err(e) => e,
         }
@@ -556,86 +1165,414 @@ impl<'a> MemFdExecutable<'a> {
     }
 
     fn write_prog<Fd: AsFd>(&self, fd: &Fd) -> Result<()> {
-        if let Ok(n) = write(fd, self.code) {
-            if n != self.code.len() {
-                return Err(Error::new(
-                    ErrorKind::BrokenPipe,
-                    "Failed to write to memfd",
-                ));
-            }
-        } else {
-            return Err(Error::last_os_error());
+        match self.code.as_image() {
+            Some(image) => image.copy_to(fd),
+            None => write_prog(fd, self.code.as_bytes().unwrap_or_default()),
+        }
+    }
+
+    /// Decide on the strategy chain to try, whether to close the memfd on exec, and load the
+    /// emulator, the bundle and the preloads. `NO_MEMFDEXEC=1` only ever removes the strategies
+    /// which don't touch the disk, it never adds any.
+    fn launch_plan(&self, reporter: &Reporter) -> LaunchResult<LaunchPlan> {
+        let mut strategies = self.strategies.clone().unwrap_or_else(LaunchStrategy::defaults);
+        if env::var("NO_MEMFDEXEC").unwrap_or_default() == "1" {
+            reporter.event(LaunchEvent::MemfdDisabled);
+            strategies.retain(LaunchStrategy::touches_disk);
+        }
+        let info = self.elf_info().ok().filter(|_| self.interpreter.is_none());
+        let emulator = match (&self.emulator, &info) {
+            (Some(code), Some(info)) if !info.is_native() => Some(Emulator::load(code, info)?),
+            _ => None,
+        };
+        let bundled = self.loader.is_some() || !self.libraries.is_empty();
+        let bundle = match info {
+            Some(ref info) if bundled && !info.is_static() => Some(Bundle::load(
+                self.loader.as_ref(),
+                &self.libraries,
+                info,
+            )?),
+            _ => None,
+        };
+        let cloexec = self.interpreter.is_none()
+            && emulator.is_none()
+            && bundle.is_none()
+            && self
+                .memfd_cloexec
+                .unwrap_or_else(|| !needs_interpreter(&self.program_header()));
+        let preloads = self
+            .preloads
+            .iter()
+            .map(|(name, code)| Ok(load_memfd(name, code)?.0))
+            .collect::<LaunchResult<Vec<_>>>()?;
+        Ok(LaunchPlan {
+            strategies,
+            cloexec,
+            emulator,
+            bundle,
+            preloads,
+        })
+    }
+
+    /// A new session makes the program the leader of its own group, after which `setpgid`
+    /// would fail with `EPERM` in the child, so refuse the combination up front.
+    fn check_process_group(&self) -> Result<()> {
+        if self.setsid && self.pgroup.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid argument: setsid and process_group can't be combined",
+            ));
         }
         Ok(())
     }
 
-    fn fallback_exec(&self, argv: &Vec<&CStr>, envp: &Vec<&CStr>) -> Result<()> {
-        eprint!(" Trying tmpfile in ");
+    /// The checks enabled by `check_elf`. Programs the kernel runs through an interpreter
+    /// are left to the interpreter, but a foreign architecture binary is fine if a
+    /// `binfmt_misc` emulator will run it.
+    fn preflight(&self) -> LaunchResult {
+        if !self.check_elf || self.interpreter.is_some() {
+            return Ok(());
+        }
+        let header = self.program_header();
+        if header.starts_with(b"#!") {
+            return Ok(());
+        }
+        let emulated = self.emulator.is_some() || needs_interpreter(&header);
+        match self.elf_info() {
+            Ok(info) => info.check(emulated, self.loader.is_some()),
+            Err(ElfError::NotElf) if emulated => Ok(()),
+            Err(err) => Err(err),
+        }
+        .map_err(MemFdExecError::Elf)
+    }
 
-        let pid = process::id();
-        let uid = unsafe { libc::getuid() };
+    /// The start of the program, which is what the kernel picks a binary format by.
+    fn program_header(&self) -> Vec<u8> {
+        match self.code.as_image() {
+            Some(image) => image.read_at(0, HEADER_LEN),
+            None => {
+                let code = self.code.as_bytes().unwrap_or_default();
+                code[..code.len().min(HEADER_LEN)].to_vec()
+            }
+        }
+    }
 
-        for dir in [
-            env::temp_dir().to_str().unwrap_or_default(),
-            "/dev/shm",
-            &format!("{}/.cache", env::home_dir().unwrap_or_default().to_string_lossy())
-        ] {
-            eprint!("{dir}... ");
+    unsafe fn try_strategy(
+        &self,
+        strategy: &LaunchStrategy,
+        plan: &LaunchPlan,
+        memfd: &mut Option<(OwnedFd, MFdFlags)>,
+        argv: &Vec<&CStr>,
+        envp: &Vec<&CStr>,
+        reporter: &Reporter,
+    ) -> LaunchResult {
+        let launching = || LaunchInfo {
+            strategy: strategy.clone(),
+            memfd_flags: None,
+        };
+        match *strategy {
+            LaunchStrategy::TmpFile(ref dirs) => {
+                reporter.launching(launching());
+                self.tmpfile_exec(dirs, plan, argv, envp, reporter)
+            }
+            LaunchStrategy::OTmpFile(ref dirs) => {
+                reporter.launching(launching());
+                self.o_tmpfile_exec(dirs, plan, argv, envp, reporter)
+            }
+            LaunchStrategy::Userland => {
+                reporter.launching(launching());
+                self.userland_exec(argv, envp)
+            }
+            _ => self.memfd_exec(strategy, plan, memfd, argv, envp, reporter),
+        }
+    }
 
-            let path_dir = PathBuf::from(format!("{dir}/mfd{uid}{pid}"));
-            let path = path_dir.join(&self.name);
+    /// Get the memfd holding the program and the flags it was created with: the image if
+    /// there is one, otherwise a memfd the code is written to the first time a memfd strategy
+    /// needs it, and which is shared by all the memfd strategies after that.
+    fn memfd<'m>(
+        &'m self,
+        cache: &'m mut Option<(OwnedFd, MFdFlags)>,
+    ) -> LaunchResult<(BorrowedFd<'m>, MFdFlags)> {
+        if let Some(image) = self.code.as_image() {
+            return Ok((image.as_fd(), image.memfd_flags()));
+        }
+        if cache.is_none() {
+            // Map the executable last, because it's a huge hit to memory if something else failed
+            let (mfd, flags) = create_exec_memfd(
+                CString::new(&*self.name).unwrap().as_c_str(),
+                MFdFlags::MFD_CLOEXEC,
+            )
+            .map_err(|errno| MemFdExecError::MemfdCreate(errno as i32))?;
+            if !is_exe(Path::new(&format!("/proc/self/fd/{}", mfd.as_raw_fd()))) {
+                return Err(MemFdExecError::MemfdCreate(libc::EACCES));
+            }
+            self.write_prog(&mfd)
+                .map_err(|e| MemFdExecError::WriteImage(errno_of(&e)))?;
+            *cache = Some((mfd, flags));
+        }
+        let (ref mfd, flags) = *cache.as_ref().unwrap();
+        Ok((mfd.as_fd(), flags))
+    }
+
+    unsafe fn memfd_exec(
+        &self,
+        strategy: &LaunchStrategy,
+        plan: &LaunchPlan,
+        cache: &mut Option<(OwnedFd, MFdFlags)>,
+        argv: &Vec<&CStr>,
+        envp: &Vec<&CStr>,
+        reporter: &Reporter,
+    ) -> LaunchResult {
+        let fexecve_err = |e: Error| MemFdExecError::Fexecve(errno_of(&e));
+        let (fd, mut flags) = self.memfd(cache)?;
+        let cloexec = plan.cloexec && *strategy != LaunchStrategy::MemfdNoCloexec;
+        // The memfd is CLOEXEC in the parent. We are in the forked child now, so changing the
+        // flag on our copy of the descriptor table doesn't affect anybody else.
+        set_cloexec(fd.as_raw_fd(), cloexec).map_err(fexecve_err)?;
+        flags.set(MFdFlags::MFD_CLOEXEC, cloexec);
+        reporter.launching(LaunchInfo {
+            strategy: strategy.clone(),
+            memfd_flags: Some(flags.bits()),
+        });
+        let execveat = *strategy == LaunchStrategy::ExecveatEmptyPath;
+        let res = self.exec_fd(fd, execveat, plan, argv, envp);
+        // Don't leak the memfd into whatever the next strategy ends up executing
+        set_cloexec(fd.as_raw_fd(), true).map_err(fexecve_err)?;
+        res.map_err(fexecve_err)
+    }
 
-            let (file, fd_raw) = create_and_open_file(&path)?;
+    fn tmpfile_exec(
+        &self,
+        dirs: &[PathBuf],
+        plan: &LaunchPlan,
+        argv: &Vec<&CStr>,
+        envp: &Vec<&CStr>,
+        reporter: &Reporter,
+    ) -> LaunchResult {
+        let mut last_dir = PathBuf::new();
+        for dir in dirs {
+            reporter.event(LaunchEvent::TryingTmpfile { dir: dir.clone() });
+
+            let tmpfile_err = |err: &Error| MemFdExecError::FallbackTmpfile {
+                dir: dir.clone(),
+                errno: errno_of(err),
+            };
+
+            let (tmpfile, file) =
+                TmpFile::create(dir, &self.tmpfile, &self.name).map_err(|e| tmpfile_err(&e))?;
+            let path = tmpfile.path();
 
             if !is_exe(&path) {
-                unsafe { close(fd_raw) };
-                fs::remove_dir_all(path_dir)?;
+                drop(file);
+                tmpfile.remove();
+                reporter.event(LaunchEvent::Failed(tmpfile_err(&Error::from_raw_os_error(
+                    libc::EACCES,
+                ))));
+                last_dir = dir.clone();
                 continue
             }
-            eprintln!();
 
-            self.write_prog(&file)?;
-            drop(file);
-            unsafe { close(fd_raw) };
+            let cleanup = self
+                .write_prog(&file)
+                .and_then(|_| {
+                    // The file has to be closed before it can be executed
+                    drop(file);
+                    reporter.remove_after_exec(&tmpfile)
+                })
+                .map_err(|e| {
+                    tmpfile.remove();
+                    tmpfile_err(&e)
+                })?;
+
+            let res = if plan.cloexec {
+                do_execve(&path, argv, envp)
+            } else {
+                // The interpreter opens the program after the exec, when the tmpfile may
+                // already be removed, so hand it a descriptor which keeps the file around
+                File::open(&path).and_then(|exe| {
+                    unsafe { set_cloexec(exe.as_raw_fd(), false)? };
+                    self.exec_fd(exe.as_fd(), false, plan, argv, envp)
+                })
+            };
+            let err = res.unwrap_err();
+            drop(cleanup);
+            tmpfile.remove();
+            return Err(tmpfile_err(&err));
+        }
+        // None of the directories allowed executing files
+        Err(MemFdExecError::FallbackTmpfile {
+            dir: last_dir,
+            errno: libc::EACCES,
+        })
+    }
 
-            match unsafe { fork() } {
-                Ok(ForkResult::Parent { child: child_pid }) => {
-                    spawn(move || waitpid(child_pid, None) );
-                    return do_execve(&path.as_path().to_string_lossy(), argv, envp)
-                }
-                Ok(ForkResult::Child) => {
-                    try_setsid();
-                    sleep(Duration::from_millis(2));
-                    let _ = fs::remove_dir_all(&path_dir);
-                    exit(0)
+    /// Map the program into this process and jump to it. Scripts can't be run that way.
+    unsafe fn userland_exec(&self, argv: &[&CStr], envp: &[&CStr]) -> LaunchResult {
+        let userland_err = |e: Error| MemFdExecError::Userland(errno_of(&e));
+        if self.interpreter.is_some() {
+            return Err(MemFdExecError::Userland(libc::ENOEXEC));
+        }
+        let res = match self.code.as_image() {
+            Some(image) => userland::exec(&image.read_at(0, image.len()), argv, envp),
+            None => userland::exec(self.code.as_bytes().unwrap_or_default(), argv, envp),
+        };
+        res.map_err(userland_err)
+    }
+
+    /// Write the program to an unnamed `O_TMPFILE`, which has no path anybody could find it
+    /// by and disappears by itself once the last descriptor to it is closed, so there is
+    /// nothing to clean up afterwards.
+    fn o_tmpfile_exec(
+        &self,
+        dirs: &[PathBuf],
+        plan: &LaunchPlan,
+        argv: &Vec<&CStr>,
+        envp: &Vec<&CStr>,
+        reporter: &Reporter,
+    ) -> LaunchResult {
+        let mut last_err = MemFdExecError::NoLaunchStrategy;
+        for dir in dirs {
+            reporter.event(LaunchEvent::TryingTmpfile { dir: dir.clone() });
+            if !matches!(last_err, MemFdExecError::NoLaunchStrategy) {
+                reporter.event(LaunchEvent::Failed(last_err.clone()));
+            }
+            let Err(err) = self.try_o_tmpfile(dir, plan, argv, envp) else {
+                return Ok(());
+            };
+            last_err = MemFdExecError::FallbackTmpfile {
+                dir: dir.clone(),
+                errno: errno_of(&err),
+            };
+        }
+        Err(last_err)
+    }
+
+    fn try_o_tmpfile(
+        &self,
+        dir: &Path,
+        plan: &LaunchPlan,
+        argv: &Vec<&CStr>,
+        envp: &Vec<&CStr>,
+    ) -> Result<()> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .mode(self.tmpfile.mode)
+            .custom_flags(libc::O_TMPFILE | libc::O_CLOEXEC)
+            .open(dir)?;
+        self.write_prog(&file)?;
+        // Executing a file which is still open for writing fails with ETXTBSY, so trade the
+        // descriptor for a read-only one
+        let exe = File::options()
+            .read(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+        drop(file);
+        if !plan.cloexec {
+            unsafe { set_cloexec(exe.as_raw_fd(), false)? };
+        }
+        self.exec_fd(exe.as_fd(), false, plan, argv, envp)
+    }
+
+    /// Execute the program in `fd`: directly, through the loader of its bundle, as the script
+    /// of the interpreter, or as the guest of the emulator (which can be the loader again).
+    /// The descriptor must not be closed on exec for anything but the program itself to be
+    /// able to open it.
+    fn exec_fd(
+        &self,
+        fd: BorrowedFd,
+        execveat: bool,
+        plan: &LaunchPlan,
+        argv: &Vec<&CStr>,
+        envp: &Vec<&CStr>,
+    ) -> Result<()> {
+        let program = CString::new(format!("/proc/self/fd/{}", fd.as_raw_fd())).unwrap();
+        let (guest, guest_argv) = match plan.bundle {
+            Some(ref bundle) => {
+                for library in &bundle.libraries {
+                    unsafe { set_cloexec(library.as_raw_fd(), false)? };
                 }
-                Err(err) => {
-                    eprintln!("fork error: {err}");
-                    let _ = fs::remove_dir_all(&path_dir);
-                    return Err(Error::other(format!("fork failed: {}", err)));
+                let guest_argv = bundle.argv(&program, argv);
+                match bundle.loader {
+                    Loader::Memfd(ref loader) if plan.emulator.is_none() => {
+                        return do_fexecve(loader, &guest_argv, envp);
+                    }
+                    // qemu-user opens the guest by path, so the loader has to stay open for it
+                    Loader::Memfd(ref loader) => unsafe {
+                        set_cloexec(loader.as_raw_fd(), false)?
+                    },
+                    Loader::Path(ref path) if plan.emulator.is_none() => {
+                        execve(path, &guest_argv, envp)?;
+                    }
+                    Loader::Path(_) => {}
                 }
+                (bundle.loader.path(), guest_argv)
             }
+            None => (program.clone(), argv.clone()),
+        };
+        if let Some(ref emulator) = plan.emulator {
+            // qemu-user takes the argv[0] of the guest with `-0`
+            let argv = [emulator.name.as_c_str(), c"-0", argv[0], guest.as_c_str()]
+                .into_iter()
+                .chain(guest_argv.iter().skip(1).copied())
+                .collect::<Vec<_>>();
+            if !emulator.cloexec {
+                unsafe { set_cloexec(emulator.fd.as_raw_fd(), false)? };
+            }
+            return do_fexecve(&emulator.fd, &argv, envp);
         }
+        let Some(ref interpreter) = self.interpreter else {
+            return if execveat {
+                do_execveat(fd, argv, envp)
+            } else {
+                do_fexecve(&fd, argv, envp)
+            };
+        };
+        let argv = [interpreter.as_c_str()]
+            .into_iter()
+            .chain(self.interpreter_args.iter().map(CString::as_c_str))
+            .chain([program.as_c_str()])
+            .chain(argv.iter().skip(1).copied())
+            .collect::<Vec<_>>();
+        execve(argv[0], &argv, envp)?;
         Ok(())
     }
 
+    /// Set up the program's process and execute it. `parent` is the process the program
+    /// should see as its parent, to tell whether it died before `parent_death_signal` was
+    /// set up.
     unsafe fn do_exec(
-        &mut self,
+        &self,
         stdio: ChildPipes,
-        maybe_envp: Option<Vec<CString>>,
-    ) -> Result<()> {
+        envp: Vec<CString>,
+        plan: &LaunchPlan,
+        parent: pid_t,
+        reporter: &Reporter,
+    ) -> LaunchResult {
+        let dup2_err = |e: Error| MemFdExecError::Dup2(errno_of(&e));
         if let Some(fd) = stdio.stdin.fd() {
-            cvt_r(|| libc::dup2(fd, libc::STDIN_FILENO))?;
+            cvt_r(|| libc::dup2(fd, libc::STDIN_FILENO)).map_err(dup2_err)?;
         }
         if let Some(fd) = stdio.stdout.fd() {
-            cvt_r(|| libc::dup2(fd, libc::STDOUT_FILENO))?;
+            cvt_r(|| libc::dup2(fd, libc::STDOUT_FILENO)).map_err(dup2_err)?;
         }
         if let Some(fd) = stdio.stderr.fd() {
-            cvt_r(|| libc::dup2(fd, libc::STDERR_FILENO))?;
+            cvt_r(|| libc::dup2(fd, libc::STDERR_FILENO)).map_err(dup2_err)?;
         }
 
         if let Some(ref cwd) = *self.get_cwd() {
-            cvt(libc::chdir(cwd.as_ptr()))?;
+            cvt(libc::chdir(cwd.as_ptr())).map_err(|e| MemFdExecError::Chdir(errno_of(&e)))?;
+        }
+
+        {
+            let pgroup_err = |e: Error| MemFdExecError::ProcessGroup(errno_of(&e));
+            if self.setsid {
+                cvt(libc::setsid()).map_err(pgroup_err)?;
+            }
+            if let Some(pgroup) = self.pgroup {
+                cvt(libc::setpgid(0, pgroup)).map_err(pgroup_err)?;
+            }
         }
 
         {
@@ -646,23 +1583,33 @@ impl<'a> MemFdExecutable<'a> {
             // UNIX programs do not reset these things on their own, so we
             // need to clean things up now to avoid confusing the program
             // we're about to run.
+            let signals_err = |e: Error| MemFdExecError::Signals(errno_of(&e));
             let mut set = MaybeUninit::<libc::sigset_t>::uninit();
-            cvt(sigemptyset(set.as_mut_ptr()))?;
+            cvt(sigemptyset(set.as_mut_ptr())).map_err(signals_err)?;
             cvt_nz(libc::pthread_sigmask(
                 libc::SIG_SETMASK,
                 set.as_ptr(),
                 null_mut(),
-            ))?;
+            ))
+            .map_err(signals_err)?;
 
             {
                 let ret = signal(libc::SIGPIPE, libc::SIG_DFL);
                 if ret == libc::SIG_ERR {
-                    return Err(Error::last_os_error());
+                    return Err(signals_err(Error::last_os_error()));
                 }
             }
         }
 
-        // TODO: Env resetting isn't implemented because we're using fexecve not execvp
+        if let Some(sig) = self.pdeathsig {
+            cvt(libc::prctl(libc::PR_SET_PDEATHSIG, sig as libc::c_ulong))
+                .map_err(|e| MemFdExecError::ParentDeathSignal(errno_of(&e)))?;
+            // The parent may have died before the signal was set up, then we have been
+            // reparented and won't get it
+            if libc::getppid() != parent {
+                libc::kill(libc::getpid(), sig);
+            }
+        }
 
         let argv = self
             .get_argv()
@@ -670,52 +1617,25 @@ impl<'a> MemFdExecutable<'a> {
             .map(|s| s.as_c_str())
             .collect::<Vec<_>>();
 
-        let maybe_envp = maybe_envp.unwrap_or_default();
+        let envp = envp.iter().map(|s| s.as_c_str()).collect::<Vec<_>>();
 
-        let envp = maybe_envp.iter().map(|s| s.as_c_str()).collect::<Vec<_>>();
-
-        if env::var("NO_MEMFDEXEC").unwrap_or_default() == "1" {
-            eprint!("memfd-exec is disabled.");
-            self.fallback_exec(&argv, &envp)?
-        } else {
-            // TODO: add detect for qemu emulator
-            fn is_running_in_qemu() -> bool {
-                true
-            }
-            let memfd_flags = if is_running_in_qemu() {
-                MFdFlags::empty()
-            } else {
-                MFdFlags::MFD_CLOEXEC
-            };
+        // The preloaded libraries are loaded by the program's loader after the exec
+        for preload in &plan.preloads {
+            set_cloexec(preload.as_raw_fd(), false)
+                .map_err(|e| MemFdExecError::Fexecve(errno_of(&e)))?;
+        }
 
-            // Map the executable last, because it's a huge hit to memory if something else failed
-            let mut mfd_res = memfd_create(
-                CString::new(&*self.name).unwrap().as_c_str(),
-                memfd_flags,
-            );
-            if let Ok(mfd) = &mfd_res {
-                let mfd_raw = mfd.as_raw_fd();
-                if !is_exe(Path::new(&format!("/proc/self/fd/{}", &mfd_raw))) {
-                    close(mfd_raw);
-                    mfd_res = Err(Errno::EACCES)
-                }
+        let mut memfd = None;
+        let mut failed = None;
+        for strategy in &plan.strategies {
+            if let Some(err) = failed.take() {
+                reporter.event(LaunchEvent::Failed(err));
             }
-            match mfd_res {
-                Ok(mfd) => {
-                    self.write_prog(&mfd)?;
-                    let mut res = do_fexecve(mfd.as_raw_fd(), &argv, &envp);
-                    if res.is_err() {
-                        eprint!("Failed to exec memfd: {}.", res.unwrap_err());
-                        res = self.fallback_exec(&argv, &envp)
-                    }
-                    return res;
-                }
-                Err(err) => {
-                    eprint!("Failed to create memfd for exec: {err}.");
-                    self.fallback_exec(&argv, &envp)?
-                }
+            match self.try_strategy(strategy, plan, &mut memfd, &argv, &envp, reporter) {
+                Ok(()) => return Ok(()),
+                Err(err) => failed = Some(err),
             }
         }
-        Ok(())
+        Err(failed.unwrap_or(MemFdExecError::NoLaunchStrategy))
     }
 }
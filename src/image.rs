@@ -0,0 +1,326 @@
+//! A sealed memfd holding an executable image which can be shared between any number of
+//! `MemFdExecutable` builders. The image is written and sealed once in the parent, and every
+//! spawned child only has to `fexecve` the inherited descriptor.
+
+use std::{
+    ffi::{CStr, CString, OsStr},
+    fs::File,
+    io::{Error, ErrorKind, Read, Result},
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+        unix::{fs::OpenOptionsExt, prelude::OsStrExt},
+    },
+    sync::Arc,
+};
+
+use nix::{
+    errno::Errno,
+    sys::{
+        memfd::{memfd_create, MFdFlags},
+        stat::fstat,
+    },
+    unistd::write,
+};
+
+use crate::{
+    cvt::{cvt, cvt_r},
+    error::{errno_of, MemFdExecError},
+};
+
+/// The seals applied to every image. Once these are set, the contents of the memfd can never
+/// change again, so it is safe to hand the descriptor to as many children as we like.
+const IMAGE_SEALS: libc::c_int =
+    libc::F_SEAL_WRITE | libc::F_SEAL_GROW | libc::F_SEAL_SHRINK | libc::F_SEAL_SEAL;
+
+/// `memfd_create` flags added in Linux 6.3 which are not in libc yet. `MFD_EXEC` asks for an
+/// executable memfd and `MFD_NOEXEC_SEAL` for one which can never be made executable.
+pub(crate) const MFD_NOEXEC_SEAL: libc::c_uint = 0x8;
+pub(crate) const MFD_EXEC: libc::c_uint = 0x10;
+
+/// An executable image stored in a sealed memfd. Creating the image copies the code into
+/// memory exactly once; cloning it is cheap and shares the same descriptor.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::fs::read;
+///
+/// use memfd_exec::{MemFdExecutable, MemFdImage};
+///
+/// let image = MemFdImage::new("cat", &read("/bin/cat").unwrap()).unwrap();
+///
+/// for _ in 0..10 {
+///     MemFdExecutable::from_image("cat", &image)
+///         .arg("Cargo.toml")
+///         .status()
+///         .expect("failed to run cat");
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MemFdImage {
+    inner: Arc<ImageInner>,
+}
+
+#[derive(Debug)]
+struct ImageInner {
+    fd: OwnedFd,
+    name: String,
+    len: usize,
+    flags: MFdFlags,
+}
+
+impl MemFdImage {
+    /// Create a new sealed image with the given name and code. The name is only used to
+    /// label the memfd (it shows up in `/proc/<pid>/fd`), it is not the argv\[0\] of the
+    /// programs launched from the image.
+    pub fn new<S: AsRef<OsStr>>(name: S, code: &[u8]) -> Result<Self> {
+        Self::build(name.as_ref(), |fd| {
+            write_prog(fd, code)?;
+            Ok(code.len())
+        })
+    }
+
+    /// Create a new sealed image with the given name, streaming the code from `reader`
+    /// straight into the memfd. The code is never buffered as a whole, so this is the way to
+    /// load programs received from a socket or a decompressor.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::fs::File;
+    ///
+    /// use memfd_exec::{MemFdExecutable, MemFdImage};
+    ///
+    /// let image = MemFdImage::from_reader("ls", File::open("/bin/ls").unwrap()).unwrap();
+    /// MemFdExecutable::from_image("ls", &image)
+    ///     .status()
+    ///     .expect("failed to run ls");
+    /// ```
+    pub fn from_reader<S: AsRef<OsStr>, R: Read>(name: S, reader: R) -> Result<Self> {
+        Self::build(name.as_ref(), |fd| stream_prog(fd, reader))
+    }
+
+    /// Like `from_reader`, but reserves `len` bytes for the memfd up front. Use this when the
+    /// size of the code is known in advance (for example from a `Content-Length` header), so
+    /// running out of memory is detected before anything is read.
+    pub fn from_reader_with_len<S: AsRef<OsStr>, R: Read>(
+        name: S,
+        reader: R,
+        len: u64,
+    ) -> Result<Self> {
+        Self::build(name.as_ref(), |fd| {
+            preallocate(fd, len).map_err(write_image_err)?;
+            stream_prog(fd, reader)
+        })
+    }
+
+    fn build<F: FnOnce(&OwnedFd) -> Result<usize>>(name: &OsStr, fill: F) -> Result<Self> {
+        let (fd, flags) = create_sealable_memfd(name)?;
+        let len = fill(&fd)?;
+        seal(&fd).map_err(|e| MemFdExecError::Seal(errno_of(&e)))?;
+        let fd = reopen_read_only(fd);
+        Ok(Self {
+            inner: Arc::new(ImageInner {
+                fd,
+                name: name.to_string_lossy().into_owned(),
+                len,
+                flags,
+            }),
+        })
+    }
+
+    /// The name the image was created with.
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    /// The size of the image in bytes.
+    pub fn len(&self) -> usize {
+        self.inner.len
+    }
+
+    /// Whether the image is empty.
+    pub fn is_empty(&self) -> bool {
+        self.inner.len == 0
+    }
+
+    /// The flags the memfd was created with.
+    pub(crate) fn memfd_flags(&self) -> MFdFlags {
+        self.inner.flags
+    }
+
+    /// Read `len` bytes of the image at `offset`, or fewer if the image ends before.
+    pub(crate) fn read_at(&self, offset: u64, len: usize) -> Vec<u8> {
+        let available = (self.inner.len as u64).saturating_sub(offset);
+        let mut buf = vec![0u8; len.min(available.try_into().unwrap_or(usize::MAX))];
+        let n = unsafe {
+            libc::pread(
+                self.inner.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                offset as libc::off_t,
+            )
+        };
+        buf.truncate(n.max(0) as usize);
+        buf
+    }
+
+    /// Copy the contents of the image into another file, for example a tmpfile used as a
+    /// fallback when the memfd itself cannot be executed.
+    pub(crate) fn copy_to<Fd: AsFd>(&self, fd: &Fd) -> Result<()> {
+        self.try_copy_to(fd).map_err(write_image_err)
+    }
+
+    fn try_copy_to<Fd: AsFd>(&self, fd: &Fd) -> Result<()> {
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut offset = 0usize;
+        while offset < self.inner.len {
+            let n = cvt_r(|| unsafe {
+                libc::pread(
+                    self.inner.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    offset as libc::off_t,
+                )
+            })? as usize;
+            if n == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "memfd image is shorter than expected",
+                ));
+            }
+            write_all(fd, &buf[..n])?;
+            offset += n;
+        }
+        verify_size(fd, offset)
+    }
+}
+
+impl AsFd for MemFdImage {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.inner.fd.as_fd()
+    }
+}
+
+impl AsRawFd for MemFdImage {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.fd.as_raw_fd()
+    }
+}
+
+fn create_sealable_memfd(name: &OsStr) -> Result<(OwnedFd, MFdFlags)> {
+    let name = CString::new(name.as_bytes())
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "nul byte found in image name"))?;
+    create_exec_memfd(
+        name.as_c_str(),
+        MFdFlags::MFD_CLOEXEC | MFdFlags::MFD_ALLOW_SEALING,
+    )
+    .map_err(|errno| MemFdExecError::MemfdCreate(errno as i32).into())
+}
+
+/// Create a memfd which can be executed, and return the flags it was actually created with.
+/// Since Linux 6.3 that takes `MFD_EXEC` (leaving it out gets a warning in the kernel log, and
+/// a non-executable memfd when `vm.memfd_noexec` is set). Older kernels reject the flag with
+/// `EINVAL`, but their memfds are always executable, so we just try again without it.
+pub(crate) fn create_exec_memfd(name: &CStr, flags: MFdFlags) -> nix::Result<(OwnedFd, MFdFlags)> {
+    let exec_flags = flags | MFdFlags::from_bits_retain(MFD_EXEC);
+    match memfd_create(name, exec_flags) {
+        Err(Errno::EINVAL) => memfd_create(name, flags).map(|fd| (fd, flags)),
+        res => res.map(|fd| (fd, exec_flags)),
+    }
+}
+
+fn seal<Fd: AsFd>(fd: &Fd) -> Result<()> {
+    cvt(unsafe { libc::fcntl(fd.as_fd().as_raw_fd(), libc::F_ADD_SEALS, IMAGE_SEALS) }).map(drop)
+}
+
+/// Trade the writable descriptor returned by `memfd_create` for a read-only one, so no
+/// writer is left around when the image is executed. If `/proc` is unavailable we just keep
+/// the original descriptor, the seals already guarantee nobody can modify it.
+fn reopen_read_only(fd: OwnedFd) -> OwnedFd {
+    File::options()
+        .read(true)
+        .custom_flags(libc::O_CLOEXEC)
+        .open(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+        .map(OwnedFd::from)
+        .unwrap_or(fd)
+}
+
+/// Reserve `len` bytes of backing memory for the file without changing its size. Not every
+/// filesystem supports this, in which case the space is simply allocated while writing.
+fn preallocate<Fd: AsFd>(fd: &Fd, len: u64) -> Result<()> {
+    let res = cvt(unsafe {
+        libc::fallocate(
+            fd.as_fd().as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            len as libc::off_t,
+        )
+    });
+    match res {
+        Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+        other => other.map(drop),
+    }
+}
+
+/// Copy everything from `reader` into the given file descriptor, one chunk at a time, and
+/// return the number of bytes written.
+pub(crate) fn stream_prog<Fd: AsFd, R: Read>(fd: &Fd, mut reader: R) -> Result<usize> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        write_all(fd, &buf[..n]).map_err(write_image_err)?;
+        total += n;
+    }
+    verify_size(fd, total).map_err(write_image_err)?;
+    Ok(total)
+}
+
+/// Write the program code to the given file descriptor, which must be empty, and make sure
+/// the whole program actually ended up in the file.
+pub(crate) fn write_prog<Fd: AsFd>(fd: &Fd, code: &[u8]) -> Result<()> {
+    write_all(fd, code)
+        .and_then(|_| verify_size(fd, code.len()))
+        .map_err(write_image_err)
+}
+
+fn write_image_err(err: Error) -> Error {
+    MemFdExecError::WriteImage(errno_of(&err)).into()
+}
+
+/// Write all of `buf`, retrying on short writes and `EINTR`.
+fn write_all<Fd: AsFd>(fd: &Fd, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        match write(fd, buf) {
+            Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "Failed to write to memfd")),
+            Ok(n) => buf = &buf[n..],
+            Err(Errno::EINTR) => {}
+            // tmpfs (and so memfd) reports its size limit and memory cgroup limits as ENOSPC
+            Err(Errno::ENOSPC | Errno::EFBIG | Errno::ENOMEM) => {
+                return Err(Error::new(
+                    ErrorKind::StorageFull,
+                    "ran out of space while writing the program",
+                ))
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Check the file really is `len` bytes long after writing the program to it.
+fn verify_size<Fd: AsFd>(fd: &Fd, len: usize) -> Result<()> {
+    let size = fstat(fd.as_fd())?.st_size;
+    if size as u64 != len as u64 {
+        return Err(Error::other(format!(
+            "wrote {len} bytes of the program but the file is {size} bytes long"
+        )));
+    }
+    Ok(())
+}
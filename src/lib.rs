@@ -50,17 +50,41 @@
 // #![feature(never_type)]
 
 mod anon_pipe;
+#[cfg(feature = "tokio")]
+mod async_child;
+mod binfmt;
 mod child;
+mod code;
 mod command_env;
 mod cvt;
+mod elf;
+mod error;
+mod event;
 mod executable;
 mod file_desc;
+mod image;
+mod library;
 mod output;
+mod probe;
 mod process;
+mod report;
 mod stdio;
+mod strategy;
+mod tmpfile;
+mod userland;
 
+#[cfg(feature = "tokio")]
+pub use async_child::{AsyncChild, AsyncChildStderr, AsyncChildStdin, AsyncChildStdout};
 pub use child::{Child, ChildStderr, ChildStdin, ChildStdout};
+pub use code::CodeSource;
+pub use elf::{ElfClass, ElfError, ElfInfo, ElfType, Endianness};
+pub use error::MemFdExecError;
+pub use event::LaunchEvent;
 pub use executable::MemFdExecutable;
+pub use image::MemFdImage;
+pub use library::{MemFdLibrary, Symbol};
 pub use output::Output;
-pub use process::ExitStatus;
+pub use probe::{probe, Capabilities, MemfdNoexec, TmpDirStatus};
+pub use process::{ExitStatus, PidFd, Termination};
 pub use stdio::Stdio;
+pub use strategy::{LaunchInfo, LaunchStrategy};
@@ -0,0 +1,224 @@
+//! Load shared objects received as bytes into the current process, the `dlopen` counterpart
+//! of `MemFdExecutable`.
+
+use std::{
+    ffi::{c_void, CStr, CString, OsStr},
+    fmt::{Debug, Formatter, Result as FmtResult},
+    io::Result,
+    marker::PhantomData,
+    mem::size_of,
+    ops::Deref,
+    os::{
+        fd::{AsFd, AsRawFd},
+        unix::prelude::OsStrExt,
+    },
+    path::Path,
+    ptr::NonNull,
+};
+
+use crate::{
+    error::MemFdExecError,
+    image::{write_prog, MemFdImage},
+    strategy::LaunchStrategy,
+    tmpfile::{TmpFile, TmpFileOptions},
+};
+
+/// A shared object loaded into the current process from memory. The object is written to a
+/// sealed memfd and `dlopen`ed as `/proc/self/fd/N`. If that is not possible, for example
+/// because memfds can't be mapped executable on this host, it is written to a tmpfile in one
+/// of `LaunchStrategy::default_tmp_dirs()` instead, which is removed again as soon as it is
+/// loaded.
+///
+/// The object is unloaded (`dlclose`) when the `MemFdLibrary` is dropped. Symbols borrow the
+/// library, so they can't outlive it.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::fs::read;
+///
+/// use memfd_exec::MemFdLibrary;
+///
+/// let code = read("/usr/lib/x86_64-linux-gnu/libz.so.1").unwrap();
+/// let libz = MemFdLibrary::open("libz.so.1", &code, libc::RTLD_NOW).unwrap();
+/// let version = unsafe { libz.get::<extern "C" fn() -> *const libc::c_char>("zlibVersion") }
+///     .unwrap();
+/// let version = unsafe { std::ffi::CStr::from_ptr(version()) };
+/// println!("zlib {}", version.to_string_lossy());
+/// ```
+pub struct MemFdLibrary {
+    handle: NonNull<c_void>,
+    name: String,
+    /// The memfd the object was loaded from, `None` if it came from a tmpfile. It stays open
+    /// while the object is loaded: the loader tells objects apart by path, and another memfd
+    /// reusing the descriptor number would otherwise get the handle of this one.
+    image: Option<MemFdImage>,
+}
+
+// dlsym and dlclose are thread safe
+unsafe impl Send for MemFdLibrary {}
+unsafe impl Sync for MemFdLibrary {}
+
+impl MemFdLibrary {
+    /// Load the shared object `code`, labelling the memfd (or tmpfile) with `name`. `flags`
+    /// are the `dlopen` flags, like `libc::RTLD_NOW | libc::RTLD_LOCAL`.
+    pub fn open<S: AsRef<OsStr>>(name: S, code: &[u8], flags: libc::c_int) -> Result<Self> {
+        let name = name.as_ref();
+        MemFdImage::new(name, code)
+            .and_then(|image| Self::open_image(&image, flags))
+            .or_else(|err| {
+                // Report why the memfd didn't work rather than why the fallback didn't
+                Self::open_tmpfile(&name.to_string_lossy(), code, flags).map_err(|_| err)
+            })
+    }
+
+    /// Load the shared object in an existing image, without copying it. There is no tmpfile
+    /// fallback.
+    pub fn from_image(image: &MemFdImage, flags: libc::c_int) -> Result<Self> {
+        Self::open_image(image, flags)
+    }
+
+    fn open_image(image: &MemFdImage, flags: libc::c_int) -> Result<Self> {
+        let path = format!("/proc/self/fd/{}", image.as_fd().as_raw_fd());
+        let handle = dlopen(Path::new(&path), flags)?;
+        Ok(Self {
+            handle,
+            name: image.name().to_string(),
+            image: Some(image.clone()),
+        })
+    }
+
+    /// Write the object to a tmpfile in the first tmp dir which takes it, and load it from
+    /// there. The mapping keeps the object around after the file is removed.
+    fn open_tmpfile(name: &str, code: &[u8], flags: libc::c_int) -> Result<Self> {
+        let mut last_err = MemFdExecError::NoLaunchStrategy.into();
+        for dir in LaunchStrategy::default_tmp_dirs() {
+            let (tmpfile, file) = match TmpFile::create(&dir, &TmpFileOptions::default(), name) {
+                Ok(created) => created,
+                Err(err) => {
+                    last_err = err;
+                    continue;
+                }
+            };
+            let res = write_prog(&file, code).and_then(|_| {
+                drop(file);
+                dlopen(&tmpfile.path(), flags)
+            });
+            tmpfile.remove();
+            match res {
+                Ok(handle) => {
+                    return Ok(Self {
+                        handle,
+                        name: name.to_string(),
+                        image: None,
+                    })
+                }
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Look up the symbol `symbol`, typed as `T` (usually a function pointer type like
+    /// `extern "C" fn(i32) -> i32`, or a pointer to a static).
+    ///
+    /// # Safety
+    ///
+    /// `T` must be the actual type of the symbol, the library can't check it. `T` must be the
+    /// size of a pointer.
+    pub unsafe fn get<T>(&self, symbol: &str) -> Result<Symbol<'_, T>> {
+        assert_eq!(
+            size_of::<T>(),
+            size_of::<*mut c_void>(),
+            "symbols must be pointer sized"
+        );
+        let symbol = CString::new(symbol).map_err(|_| MemFdExecError::NulInArgument)?;
+        // The symbol may legitimately be NULL, only dlerror tells a missing one apart
+        libc::dlerror();
+        let ptr = libc::dlsym(self.handle.as_ptr(), symbol.as_ptr());
+        if let Some(err) = dlerror() {
+            return Err(MemFdExecError::Dlsym(err).into());
+        }
+        Ok(Symbol {
+            ptr,
+            library: PhantomData,
+        })
+    }
+
+    /// The name the library was opened with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The image the library was loaded from, `None` if it was loaded from a tmpfile.
+    pub fn image(&self) -> Option<&MemFdImage> {
+        self.image.as_ref()
+    }
+
+    /// The handle returned by `dlopen`, for use with other `dl*` functions.
+    pub fn as_raw(&self) -> *mut c_void {
+        self.handle.as_ptr()
+    }
+}
+
+impl Debug for MemFdLibrary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_struct("MemFdLibrary")
+            .field("name", &self.name)
+            .field("handle", &self.handle)
+            .field("image", &self.image)
+            .finish()
+    }
+}
+
+impl Drop for MemFdLibrary {
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.handle.as_ptr()) };
+    }
+}
+
+/// A symbol looked up in a `MemFdLibrary`, which dereferences to the `T` it was looked up as.
+pub struct Symbol<'lib, T> {
+    ptr: *mut c_void,
+    library: PhantomData<&'lib T>,
+}
+
+impl<T> Symbol<'_, T> {
+    /// The address of the symbol.
+    pub fn as_ptr(&self) -> *mut c_void {
+        self.ptr
+    }
+}
+
+impl<T> Deref for Symbol<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // `get` checked T is pointer sized
+        unsafe { &*(&self.ptr as *const *mut c_void as *const T) }
+    }
+}
+
+impl<T> Debug for Symbol<'_, T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_tuple("Symbol").field(&self.ptr).finish()
+    }
+}
+
+fn dlopen(path: &Path, flags: libc::c_int) -> Result<NonNull<c_void>> {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let handle = unsafe { libc::dlopen(path.as_ptr(), flags) };
+    NonNull::new(handle).ok_or_else(|| {
+        MemFdExecError::Dlopen(dlerror().unwrap_or_else(|| "dlopen failed".to_string())).into()
+    })
+}
+
+/// The message of the last `dl*` error on this thread, if there was one.
+fn dlerror() -> Option<String> {
+    let err = unsafe { libc::dlerror() };
+    (!err.is_null()).then(|| {
+        unsafe { CStr::from_ptr(err) }
+            .to_string_lossy()
+            .into_owned()
+    })
+}
@@ -0,0 +1,197 @@
+//! Find out up front what the current host allows, instead of discovering it in a forked
+//! child while launching a program.
+
+use std::{
+    ffi::CStr,
+    fs::read_to_string,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    sys::{
+        memfd::{memfd_create, MFdFlags},
+        statvfs::{statvfs, FsFlags},
+    },
+    unistd::{access, AccessFlags},
+};
+
+use crate::{
+    executable::is_exe,
+    image::{create_exec_memfd, MFD_EXEC, MFD_NOEXEC_SEAL},
+    strategy::LaunchStrategy,
+};
+
+/// The `vm.memfd_noexec` sysctl, which controls whether memfds may be executed at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MemfdNoexec {
+    /// The sysctl doesn't exist (kernels before 6.3 always allow executable memfds)
+    Unavailable,
+    /// `0`: memfds are executable unless created with `MFD_NOEXEC_SEAL`
+    Allowed,
+    /// `1`: memfds are only executable when created with `MFD_EXEC`
+    NoexecByDefault,
+    /// `2`: memfds can never be executed
+    Enforced,
+}
+
+/// What the current host allows when it comes to launching programs from memory or from a
+/// tmpfile. Returned by `probe`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// Whether `memfd_create` works at all. This is checked without `MFD_EXEC`, so it is
+    /// still `true` with `vm.memfd_noexec=2`, where only executable memfds are refused
+    pub memfd_create: bool,
+    /// Whether a memfd created the way `LaunchStrategy::Memfd` does is executable. With
+    /// `vm.memfd_noexec=2` the kernel refuses `MFD_EXEC` with `EACCES`, so this is `false`
+    pub memfd_executable: bool,
+    /// The value of the `vm.memfd_noexec` sysctl
+    pub memfd_noexec: MemfdNoexec,
+    /// Whether the kernel knows the `MFD_EXEC` and `MFD_NOEXEC_SEAL` flags (Linux 6.3+)
+    pub mfd_exec_flags: bool,
+    /// Whether SELinux is enforcing
+    pub selinux_enforcing: bool,
+    /// Whether the SELinux `deny_execmem` boolean is set, which together with an enforcing
+    /// policy is likely to deny executing memfds
+    pub selinux_deny_execmem: bool,
+    /// The candidate tmp dirs of the default tmpfile strategy and what they allow
+    pub tmp_dirs: Vec<TmpDirStatus>,
+}
+
+/// Whether a tmp dir can hold an executable tmpfile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TmpDirStatus {
+    /// The directory
+    pub path: PathBuf,
+    /// Whether the directory exists
+    pub exists: bool,
+    /// Whether the current user may create files in it
+    pub writable: bool,
+    /// Whether the filesystem is mounted `noexec`
+    pub noexec: bool,
+}
+
+impl TmpDirStatus {
+    fn probe(path: PathBuf) -> Self {
+        let exists = path.is_dir();
+        let writable = exists && access(&path, AccessFlags::W_OK | AccessFlags::X_OK).is_ok();
+        let noexec = statvfs(&path)
+            .map(|stat| stat.flags().contains(FsFlags::ST_NOEXEC))
+            .unwrap_or(false);
+        Self {
+            path,
+            exists,
+            writable,
+            noexec,
+        }
+    }
+
+    /// Whether a tmpfile in this directory could be executed.
+    pub fn is_usable(&self) -> bool {
+        self.exists && self.writable && !self.noexec
+    }
+}
+
+impl Capabilities {
+    /// Whether programs can be executed from a memfd, as far as we can tell without
+    /// actually executing anything.
+    pub fn can_exec_from_memory(&self) -> bool {
+        self.memfd_executable
+            && self.memfd_noexec != MemfdNoexec::Enforced
+            && !(self.selinux_enforcing && self.selinux_deny_execmem)
+    }
+
+    /// The candidate tmp dirs a tmpfile could be executed from.
+    pub fn usable_tmp_dirs(&self) -> impl Iterator<Item = &Path> {
+        self.tmp_dirs
+            .iter()
+            .filter(|dir| dir.is_usable())
+            .map(|dir| dir.path.as_path())
+    }
+
+    /// A launch strategy chain made of what should work on this host: a memfd if programs
+    /// can be executed from memory, and a tmpfile in the usable tmp dirs.
+    pub fn recommended_strategies(&self) -> Vec<LaunchStrategy> {
+        let mut strategies = Vec::new();
+        if self.can_exec_from_memory() {
+            strategies.extend(
+                LaunchStrategy::defaults()
+                    .into_iter()
+                    .filter(|strategy| !strategy.touches_disk()),
+            );
+        }
+        let dirs = self.usable_tmp_dirs().map(Path::to_path_buf).collect::<Vec<_>>();
+        if !dirs.is_empty() {
+            strategies.push(LaunchStrategy::TmpFile(dirs));
+        }
+        strategies
+    }
+}
+
+/// Report what the current host allows: whether memfds can be created and executed, what
+/// the `vm.memfd_noexec` sysctl is set to, whether the kernel knows `MFD_EXEC`, which tmp dirs
+/// are mounted `noexec` and whether SELinux is likely to get in the way. Nothing is executed,
+/// so this is cheap enough to call at startup.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::fs::read;
+///
+/// use memfd_exec::{probe, MemFdExecutable};
+///
+/// let caps = probe();
+/// if !caps.can_exec_from_memory() {
+///     eprintln!("memfds are not executable here: {caps:?}");
+/// }
+///
+/// MemFdExecutable::new("ls", read("/bin/ls").unwrap())
+///     .strategies(caps.recommended_strategies())
+///     .status()
+///     .expect("failed to run ls");
+/// ```
+pub fn probe() -> Capabilities {
+    let name = c"memfd-exec-probe";
+    // A plain memfd, which can be created even where executable ones are refused
+    let memfd_create = memfd_create(name, MFdFlags::MFD_CLOEXEC).is_ok();
+    let memfd_executable = create_exec_memfd(name, MFdFlags::MFD_CLOEXEC)
+        .is_ok_and(|(fd, _)| is_exe(Path::new(&format!("/proc/self/fd/{}", fd.as_raw_fd()))));
+    Capabilities {
+        memfd_create,
+        memfd_executable,
+        memfd_noexec: memfd_noexec(),
+        mfd_exec_flags: knows_flag(name, MFD_EXEC) && knows_flag(name, MFD_NOEXEC_SEAL),
+        selinux_enforcing: read_flag("/sys/fs/selinux/enforce"),
+        selinux_deny_execmem: read_flag("/sys/fs/selinux/booleans/deny_execmem"),
+        tmp_dirs: LaunchStrategy::default_tmp_dirs()
+            .into_iter()
+            .map(TmpDirStatus::probe)
+            .collect(),
+    }
+}
+
+fn memfd_noexec() -> MemfdNoexec {
+    match read_to_string("/proc/sys/vm/memfd_noexec").as_deref().map(str::trim) {
+        Ok("0") => MemfdNoexec::Allowed,
+        Ok("1") => MemfdNoexec::NoexecByDefault,
+        Ok("2") => MemfdNoexec::Enforced,
+        _ => MemfdNoexec::Unavailable,
+    }
+}
+
+/// Older kernels reject unknown memfd flags with `EINVAL`. Any other outcome (including
+/// `EACCES` when `vm.memfd_noexec=2` forbids `MFD_EXEC`) means the flag is known.
+fn knows_flag(name: &CStr, flag: libc::c_uint) -> bool {
+    let flags = MFdFlags::MFD_CLOEXEC | MFdFlags::from_bits_retain(flag);
+    !matches!(memfd_create(name, flags), Err(Errno::EINVAL))
+}
+
+/// Read a sysfs flag, which is `1` when set. SELinux booleans hold the current and the
+/// pending value, the first one is what counts.
+fn read_flag(path: &str) -> bool {
+    read_to_string(path).is_ok_and(|value| value.starts_with('1'))
+}
@@ -2,8 +2,15 @@
 //! <https://github.com/rust-lang/rust/blob/master/library/std/src/sys/unix/process/process_unix.rs>
 
 use libc::c_int;
+use std::collections::HashSet;
 use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs::{read, read_dir};
 use std::io::{Error, Result};
+use std::mem::zeroed;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr::null;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 use libc::pid_t;
 
@@ -11,20 +18,42 @@ use crate::cvt::{cvt, cvt_r};
 
 pub struct Process {
     pid: pid_t,
+    pidfd: Option<PidFd>,
     status: Option<ExitStatus>,
+    kill_on_drop: bool,
 }
 
 impl Process {
+    /// # Safety
+    ///
+    /// `pid` must be a child of ours which hasn't been waited for, so it can't be recycled
+    /// before the pidfd is opened.
     pub unsafe fn new(pid: pid_t) -> Self {
-        // Safety: If `pidfd` is nonnegative, we assume it's valid and otherwise unowned.
-        Process { pid, status: None }
+        Process {
+            pid,
+            pidfd: PidFd::open(pid),
+            status: None,
+            kill_on_drop: false,
+        }
+    }
+
+    pub fn set_kill_on_drop(&mut self, kill_on_drop: bool) {
+        self.kill_on_drop = kill_on_drop;
     }
 
     pub fn id(&self) -> u32 {
         self.pid as u32
     }
 
+    pub fn pidfd(&self) -> Option<&PidFd> {
+        self.pidfd.as_ref()
+    }
+
     pub fn kill(&mut self) -> Result<()> {
+        self.signal(libc::SIGKILL)
+    }
+
+    pub fn signal(&mut self, signal: c_int) -> Result<()> {
         // If we've already waited on this process then the pid can be recycled
         // and used for another process, and we probably shouldn't be killing
         // random processes, so just return an error.
@@ -33,32 +62,262 @@ impl Process {
                 std::io::ErrorKind::InvalidInput,
                 "invalid argument: can't kill an exited process",
             ))
+        } else if let Some(pidfd) = &self.pidfd {
+            pidfd.send_signal(signal)
         } else {
-            cvt(unsafe { libc::kill(self.pid, libc::SIGKILL) }).map(drop)
+            cvt(unsafe { libc::kill(self.pid, signal) }).map(drop)
         }
     }
 
+    /// SIGKILL the process, the process group `group` and every descendant of the process
+    /// found in `/proc`. The descendants are stopped top down first, so none of them can
+    /// fork a new one, or reap one of ours and let its PID be recycled, while we look.
+    pub fn kill_tree(&mut self, group: Option<pid_t>) -> Result<()> {
+        // Once the process has exited its children belong to init, we can't find them
+        let mut tree = Vec::new();
+        if self.try_wait()?.is_none() {
+            self.signal(libc::SIGSTOP)?;
+            let mut seen = HashSet::from([self.pid]);
+            loop {
+                let found = read_parents()
+                    .into_iter()
+                    .filter(|(pid, ppid)| seen.contains(ppid) && !seen.contains(pid))
+                    .map(|(pid, _)| pid)
+                    .collect::<Vec<_>>();
+                if found.is_empty() {
+                    break;
+                }
+                for pid in found {
+                    unsafe { libc::kill(pid, libc::SIGSTOP) };
+                    seen.insert(pid);
+                    tree.push(pid);
+                }
+            }
+        }
+        let group_res = match group {
+            Some(group) => match cvt(unsafe { libc::killpg(group, libc::SIGKILL) }) {
+                // Nobody is left in the group
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+                res => res.map(drop),
+            },
+            None => Ok(()),
+        };
+        // Whatever happened to the group, the stopped processes must not stay stopped
+        for pid in tree {
+            unsafe { libc::kill(pid, libc::SIGKILL) };
+        }
+        if self.status.is_none() {
+            self.kill()?;
+        }
+        group_res
+    }
+
     pub fn wait(&mut self) -> Result<ExitStatus> {
         if let Some(status) = self.status {
             return Ok(status);
         }
-        let mut status = 0 as c_int;
-        cvt_r(|| unsafe { libc::waitpid(self.pid, &mut status, 0) })?;
-        self.status = Some(ExitStatus::new(status));
-        Ok(ExitStatus::new(status))
+        if let Some(pidfd) = &self.pidfd {
+            let status = pidfd
+                .wait(0)?
+                .expect("waitid without WNOHANG returned no child");
+            self.status = Some(status);
+            return Ok(status);
+        }
+        let status = waitpid(self.pid, 0)?.expect("waitpid without WNOHANG returned no child");
+        self.status = Some(status);
+        Ok(status)
     }
 
     pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
         if let Some(status) = self.status {
             return Ok(Some(status));
         }
-        let mut status = 0 as c_int;
-        let pid = cvt(unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) })?;
-        if pid == 0 {
-            Ok(None)
-        } else {
-            self.status = Some(ExitStatus::new(status));
-            Ok(Some(ExitStatus::new(status)))
+        if let Some(pidfd) = &self.pidfd {
+            let status = pidfd.wait(libc::WNOHANG)?;
+            self.status = status;
+            return Ok(status);
+        }
+        let status = waitpid(self.pid, libc::WNOHANG)?;
+        self.status = status;
+        Ok(status)
+    }
+
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<ExitStatus>> {
+        let Some(deadline) = Instant::now().checked_add(timeout) else {
+            return self.wait().map(Some);
+        };
+        // Without a pidfd there is nothing to block on, so poll with a growing interval
+        let mut interval = Duration::from_millis(1);
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(Some(status));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            match &self.pidfd {
+                Some(pidfd) => pidfd.wait_readable(remaining)?,
+                None => {
+                    sleep(interval.min(remaining));
+                    interval = (interval * 2).min(Duration::from_millis(50));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // Reap it either way, it would otherwise linger as a zombie
+        if self.kill_on_drop && matches!(self.try_wait(), Ok(None)) && self.kill().is_ok() {
+            let _ = self.wait();
+        }
+    }
+}
+
+/// The PID and parent PID of every process in `/proc`. Processes which exit while we look are
+/// skipped.
+fn read_parents() -> Vec<(pid_t, pid_t)> {
+    let Ok(dir) = read_dir("/proc") else {
+        return Vec::new();
+    };
+    dir.flatten()
+        .filter_map(|entry| {
+            let pid = entry.file_name().to_str()?.parse().ok()?;
+            let stat = read(entry.path().join("stat")).ok()?;
+            // The name in parentheses may contain anything, the fields follow the last ')':
+            // "pid (comm) state ppid ..."
+            let end = stat.iter().rposition(|&b| b == b')')?;
+            let mut fields = std::str::from_utf8(&stat[end + 1..])
+                .ok()?
+                .split_whitespace();
+            let ppid = fields.nth(1)?.parse().ok()?;
+            Some((pid, ppid))
+        })
+        .collect()
+}
+
+/// Reap `pid`, `None` if `flags` has `WNOHANG` and it hasn't exited yet.
+fn waitpid(pid: pid_t, flags: c_int) -> Result<Option<ExitStatus>> {
+    let mut status = 0 as c_int;
+    let res = cvt_r(|| unsafe { libc::waitpid(pid, &mut status, flags) })?;
+    Ok((res != 0).then(|| ExitStatus::new(status)))
+}
+
+/// A pidfd referring to a child process. Unlike the PID, it can't come to refer to another
+/// process once the child has exited and been waited for, so signals sent through it never
+/// reach the wrong process. It becomes readable when the child exits, so it can be polled
+/// along with other file descriptors.
+pub struct PidFd {
+    fd: OwnedFd,
+    /// The child the pidfd refers to, to wait for it on kernels without `P_PIDFD`
+    pid: pid_t,
+}
+
+impl PidFd {
+    /// Open a pidfd for `pid`, `None` if the kernel doesn't support pidfds (before 5.3).
+    fn open(pid: pid_t) -> Option<Self> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        // pidfds are always close-on-exec
+        (fd >= 0).then(|| PidFd {
+            fd: unsafe { OwnedFd::from_raw_fd(fd as RawFd) },
+            pid,
+        })
+    }
+
+    fn send_signal(&self, signal: c_int) -> Result<()> {
+        cvt(unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.fd.as_raw_fd(),
+                signal,
+                null::<libc::siginfo_t>(),
+                0,
+            )
+        })
+        .map(drop)
+    }
+
+    /// Block until the child exits or `timeout` passes. Interruptions return early, the
+    /// caller checks whether the child exited.
+    fn wait_readable(&self, timeout: Duration) -> Result<()> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Round up so we don't wake up just before the deadline and spin
+        let ms = timeout
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .min(c_int::MAX as u128) as c_int;
+        match cvt(unsafe { libc::poll(&mut pfd, 1, ms) }) {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => Ok(()),
+            res => res.map(drop),
+        }
+    }
+
+    /// Reap the child, `None` if `flags` has `WNOHANG` and it hasn't exited yet.
+    fn wait(&self, flags: c_int) -> Result<Option<ExitStatus>> {
+        let mut info: libc::siginfo_t = unsafe { zeroed() };
+        let res = cvt_r(|| unsafe {
+            libc::waitid(
+                libc::P_PIDFD,
+                self.fd.as_raw_fd() as libc::id_t,
+                &mut info,
+                libc::WEXITED | flags,
+            )
+        });
+        match res {
+            // pidfds came in 5.3 but waiting on them only in 5.4
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return waitpid(self.pid, flags),
+            res => res?,
+        };
+        // With WNOHANG and no change, waitid succeeds without filling in the siginfo
+        if unsafe { info.si_pid() } == 0 {
+            return Ok(None);
+        }
+        Ok(Some(ExitStatus::from_siginfo(&info)))
+    }
+}
+
+impl AsFd for PidFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Debug for PidFd {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.debug_tuple("PidFd").field(&self.fd.as_raw_fd()).finish()
+    }
+}
+
+/// How `Child::terminate` ended the process.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Termination {
+    /// The process had already exited, it wasn't sent any signal
+    Exited(ExitStatus),
+    /// The process exited within the grace period after the termination signal
+    Terminated(ExitStatus),
+    /// The process was still running after the grace period and was killed with `SIGKILL`
+    Killed(ExitStatus),
+}
+
+impl Termination {
+    /// The exit status of the process, whichever way it ended.
+    pub fn status(&self) -> ExitStatus {
+        match *self {
+            Termination::Exited(status)
+            | Termination::Terminated(status)
+            | Termination::Killed(status) => status,
         }
     }
 }
@@ -78,6 +337,20 @@ impl ExitStatus {
         ExitStatus(status)
     }
 
+    /// Rebuild the wait status `waitpid` would have returned from the siginfo `waitid` fills
+    /// in.
+    fn from_siginfo(info: &libc::siginfo_t) -> ExitStatus {
+        let status = unsafe { info.si_status() };
+        match info.si_code {
+            libc::CLD_EXITED => ExitStatus((status & 0xff) << 8),
+            libc::CLD_KILLED => ExitStatus(status),
+            libc::CLD_DUMPED => ExitStatus(status | 0x80),
+            libc::CLD_CONTINUED => ExitStatus(0xffff),
+            // CLD_STOPPED, CLD_TRAPPED
+            _ => ExitStatus(((status & 0xff) << 8) | 0x7f),
+        }
+    }
+
     fn exited(&self) -> bool {
         libc::WIFEXITED(self.0)
     }
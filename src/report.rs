@@ -0,0 +1,127 @@
+//! The messages a forked child sends back to the parent over the CLOEXEC pipe before it execs.
+//! Each message is framed as a big-endian `u32` length followed by a `u32` kind and the
+//! payload. A successful exec closes the pipe, so everything the parent reads before EOF
+//! describes what the child went through, and a final `Error` means the launch failed.
+//! `Cleanup` names a tmpfile the parent removes once the pipe is closed, and `Launch` is sent
+//! before every exec attempt, so the last one describes how the program was launched.
+
+use std::io::Result;
+
+use crate::{
+    anon_pipe::AnonPipe,
+    error::MemFdExecError,
+    event::{dispatch, EventHook, LaunchEvent},
+    strategy::LaunchInfo,
+    tmpfile::TmpFile,
+};
+
+const KIND_ERROR: u32 = 0;
+const KIND_EVENT: u32 = 1;
+const KIND_CLEANUP: u32 = 2;
+const KIND_LAUNCH: u32 = 3;
+
+/// A message from the child to the parent.
+pub(crate) enum Report {
+    Event(LaunchEvent),
+    Error(MemFdExecError),
+    Cleanup(TmpFile),
+    Launch(LaunchInfo),
+}
+
+impl Report {
+    fn encode(&self) -> Vec<u8> {
+        let (kind, payload) = match *self {
+            Report::Event(ref event) => (KIND_EVENT, event.encode()),
+            Report::Error(ref err) => (KIND_ERROR, err.encode()),
+            Report::Cleanup(ref tmpfile) => (KIND_CLEANUP, tmpfile.encode()),
+            Report::Launch(ref info) => (KIND_LAUNCH, info.encode()),
+        };
+        let len = (payload.len() + 4) as u32;
+        let mut bytes = Vec::with_capacity(payload.len() + 8);
+        bytes.extend_from_slice(&len.to_be_bytes());
+        bytes.extend_from_slice(&kind.to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    /// Decode every message read from the pipe, or `None` if anything is malformed.
+    pub(crate) fn decode_all(mut bytes: &[u8]) -> Option<Vec<Report>> {
+        let mut reports = Vec::new();
+        while !bytes.is_empty() {
+            if bytes.len() < 8 {
+                return None;
+            }
+            let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+            // Every frame starts with its kind
+            let frame = bytes.get(4..4 + len).filter(|f| f.len() >= 4)?;
+            bytes = &bytes[4 + len..];
+            let (kind, payload) = frame.split_at(4);
+            reports.push(match u32::from_be_bytes(kind.try_into().unwrap()) {
+                KIND_EVENT => Report::Event(LaunchEvent::decode(payload)?),
+                KIND_ERROR => Report::Error(MemFdExecError::decode(payload)?),
+                KIND_CLEANUP => Report::Cleanup(TmpFile::decode(payload)?),
+                KIND_LAUNCH => Report::Launch(LaunchInfo::decode(payload)?),
+                _ => return None,
+            });
+        }
+        Some(reports)
+    }
+}
+
+/// Where a launching child sends its reports: to the parent through the CLOEXEC pipe when it
+/// was spawned, or straight to the hook when `exec` replaces the current process.
+pub(crate) enum Reporter<'a> {
+    Pipe(&'a AnonPipe),
+    Direct(Option<&'a EventHook>),
+}
+
+impl Reporter<'_> {
+    pub(crate) fn event(&self, event: LaunchEvent) {
+        match *self {
+            Reporter::Pipe(pipe) => send(pipe, &Report::Event(event)),
+            Reporter::Direct(hook) => dispatch(hook, &event),
+        }
+    }
+
+    pub(crate) fn error(&self, err: MemFdExecError) {
+        if let Reporter::Pipe(pipe) = *self {
+            send(pipe, &Report::Error(err))
+        }
+    }
+
+    /// Tell the parent how we are about to exec the program. There is nobody to tell when
+    /// the current process is replaced.
+    pub(crate) fn launching(&self, info: LaunchInfo) {
+        if let Reporter::Pipe(pipe) = *self {
+            send(pipe, &Report::Launch(info))
+        }
+    }
+
+    /// Make sure `tmpfile` is removed once the program has been executed from it: by the
+    /// parent when the pipe is closed, or by a cleaner process when there is no parent. The
+    /// returned pipe keeps the cleaner waiting, drop it to clean up right away if the exec
+    /// fails.
+    pub(crate) fn remove_after_exec(&self, tmpfile: &TmpFile) -> Result<Option<AnonPipe>> {
+        match *self {
+            Reporter::Pipe(pipe) => {
+                send(pipe, &Report::Cleanup(tmpfile.clone()));
+                Ok(None)
+            }
+            Reporter::Direct(_) => tmpfile.remove_on_exec().map(Some),
+        }
+    }
+}
+
+fn send(pipe: &AnonPipe, report: &Report) {
+    let bytes = report.encode();
+    let mut buf = &bytes[..];
+    while !buf.is_empty() {
+        match pipe.write(buf) {
+            Ok(0) => return,
+            Ok(n) => buf = &buf[n..],
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            // There is nobody left to tell
+            Err(_) => return,
+        }
+    }
+}
@@ -1,9 +1,10 @@
 use std::ffi::CStr;
-use std::fs::{File, OpenOptions};
+use std::fs::OpenOptions;
 use std::io::Result;
 use std::os::raw::c_int;
-use std::os::unix::prelude::{AsRawFd, FromRawFd};
+use std::os::unix::prelude::{AsRawFd, FromRawFd, IntoRawFd};
 use std::path::Path;
+use std::sync::Arc;
 
 use crate::anon_pipe::{anon_pipe, AnonPipe};
 use crate::file_desc::FileDesc;
@@ -29,7 +30,7 @@ pub enum ChildStdio {
 }
 
 /// Description of a stdio stream for a child process
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Stdio {
     /// Inherit the parent's stdio stream
     Inherit,
@@ -37,8 +38,9 @@ pub enum Stdio {
     Null,
     /// Use a pipe to the input or output of the child process
     MakePipe,
-    /// Use an existing file descriptor as the stdio stream
-    Fd(FileDesc),
+    /// Use an existing file descriptor as the stdio stream. The descriptor is shared
+    /// between clones of the `Stdio`.
+    Fd(Arc<FileDesc>),
 }
 
 impl Stdio {
@@ -77,7 +79,7 @@ impl Stdio {
                 opts.write(!readable);
                 let path = unsafe { CStr::from_ptr(DEV_NULL.as_ptr() as *const _) };
                 let path = Path::new(path.to_str().unwrap());
-                let fd = File::open(path)?.as_raw_fd();
+                let fd = opts.open(path)?.into_raw_fd();
                 Ok((
                     ChildStdio::Owned(unsafe { FileDesc::from_raw_fd(fd) }),
                     None,
@@ -108,7 +110,7 @@ impl Stdio {
 
 impl From<AnonPipe> for Stdio {
     fn from(pipe: AnonPipe) -> Stdio {
-        Stdio::Fd(pipe.into())
+        Stdio::Fd(Arc::new(pipe.into()))
     }
 }
 
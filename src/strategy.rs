@@ -0,0 +1,176 @@
+//! The ways an in-memory program can be launched, tried in order until one of them works.
+
+use std::{env, ffi::OsStr, os::unix::prelude::OsStrExt, path::PathBuf};
+
+/// One way of launching an in-memory program. `MemFdExecutable::strategies` takes a list of
+/// these which are tried in order, and the first one that manages to exec the program wins.
+///
+/// The default chain tries a memfd first and falls back to a tmpfile on disk. Setting the
+/// `NO_MEMFDEXEC=1` environment variable skips the strategies which don't touch the disk,
+/// but it never adds disk strategies to a chain that doesn't have them: a chain made only of
+/// in-memory strategies is guaranteed to never write the program to disk.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::fs::read;
+///
+/// use memfd_exec::{LaunchStrategy, MemFdExecutable};
+///
+/// // Never write the program to disk, fail instead
+/// MemFdExecutable::new("ls", read("/bin/ls").unwrap())
+///     .strategies(LaunchStrategy::memory_only())
+///     .status()
+///     .expect("failed to run ls from memory");
+///
+/// // Only fall back to /dev/shm, never to the home directory
+/// MemFdExecutable::new("ls", read("/bin/ls").unwrap())
+///     .strategies([
+///         LaunchStrategy::Memfd,
+///         LaunchStrategy::TmpFile(vec!["/dev/shm".into()]),
+///     ])
+///     .status()
+///     .expect("failed to run ls");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LaunchStrategy {
+    /// `fexecve` a memfd which is closed on exec, unless the program is run by a `binfmt_misc`
+    /// interpreter (see `MemFdExecutable::memfd_cloexec`)
+    Memfd,
+    /// `fexecve` a memfd which stays open in the program. Emulators like qemu-user reopen
+    /// the program through `/proc/self/fd`, which only works if the descriptor is still there.
+    MemfdNoCloexec,
+    /// Call `execveat(memfd, "", AT_EMPTY_PATH)` directly instead of going through libc
+    ExecveatEmptyPath,
+    /// Write the program to a tmpfile in the first of these directories which allows
+    /// executing files, and `execve` it
+    TmpFile(Vec<PathBuf>),
+    /// Write the program to an unnamed `O_TMPFILE` in the first of these directories which
+    /// allows executing files, and exec it through its descriptor
+    OTmpFile(Vec<PathBuf>),
+    /// Map the program into the forked child and jump to its entry point, without executing
+    /// any file at all. This works on hosts where memfds aren't executable and every writable
+    /// mount is `noexec`, but only for static (and static-PIE) programs built for this very
+    /// architecture, and only on x86_64 and aarch64. The program runs in what is left of the
+    /// address space of the child, and `/proc/self/exe` keeps naming the parent.
+    Userland,
+}
+
+impl LaunchStrategy {
+    /// The chain used when none is set: a memfd, then a tmpfile in one of the
+    /// `default_tmp_dirs`.
+    pub fn defaults() -> Vec<LaunchStrategy> {
+        vec![
+            LaunchStrategy::Memfd,
+            LaunchStrategy::TmpFile(Self::default_tmp_dirs()),
+        ]
+    }
+
+    /// A chain which never writes the program to disk.
+    pub fn memory_only() -> Vec<LaunchStrategy> {
+        vec![
+            LaunchStrategy::Memfd,
+            LaunchStrategy::MemfdNoCloexec,
+            LaunchStrategy::ExecveatEmptyPath,
+        ]
+    }
+
+    /// The directories tried by the default tmpfile strategy: the system temporary
+    /// directory, `/dev/shm` and `~/.cache`.
+    pub fn default_tmp_dirs() -> Vec<PathBuf> {
+        vec![
+            env::temp_dir(),
+            PathBuf::from("/dev/shm"),
+            env::home_dir().unwrap_or_default().join(".cache"),
+        ]
+    }
+
+    /// Whether this strategy writes the program to a filesystem other than memfd.
+    pub fn touches_disk(&self) -> bool {
+        matches!(
+            *self,
+            LaunchStrategy::TmpFile(_) | LaunchStrategy::OTmpFile(_)
+        )
+    }
+
+    /// Serialize the strategy as a tag followed by its directories, each one terminated by a
+    /// NUL byte.
+    fn encode(&self) -> Vec<u8> {
+        let (tag, dirs): (u32, &[PathBuf]) = match *self {
+            LaunchStrategy::Memfd => (1, &[]),
+            LaunchStrategy::MemfdNoCloexec => (2, &[]),
+            LaunchStrategy::ExecveatEmptyPath => (3, &[]),
+            LaunchStrategy::TmpFile(ref dirs) => (4, dirs),
+            LaunchStrategy::OTmpFile(ref dirs) => (5, dirs),
+            LaunchStrategy::Userland => (6, &[]),
+        };
+        let mut bytes = tag.to_be_bytes().to_vec();
+        for dir in dirs {
+            bytes.extend_from_slice(dir.as_os_str().as_bytes());
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    /// The inverse of `encode`.
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let (tag, payload) = bytes.split_at(4);
+        let dirs = || {
+            payload
+                .split_inclusive(|&b| b == 0)
+                .map(|dir| PathBuf::from(OsStr::from_bytes(&dir[..dir.len() - 1])))
+                .collect()
+        };
+        Some(match u32::from_be_bytes(tag.try_into().unwrap()) {
+            1 => LaunchStrategy::Memfd,
+            2 => LaunchStrategy::MemfdNoCloexec,
+            3 => LaunchStrategy::ExecveatEmptyPath,
+            4 => LaunchStrategy::TmpFile(dirs()),
+            5 => LaunchStrategy::OTmpFile(dirs()),
+            6 => LaunchStrategy::Userland,
+            _ => return None,
+        })
+    }
+}
+
+/// How a spawned program was actually launched, see `Child::launch_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LaunchInfo {
+    /// The strategy which launched the program
+    pub strategy: LaunchStrategy,
+    /// The flags of the memfd the program was executed from, as passed to `memfd_create`
+    /// (`MFD_CLOEXEC` is left out when the descriptor was kept open across the exec). `None`
+    /// when the program was executed from a tmpfile.
+    pub memfd_flags: Option<u32>,
+}
+
+impl LaunchInfo {
+    /// Serialize the info as a flag telling whether there are memfd flags, the flags, and
+    /// the strategy.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8);
+        bytes.extend_from_slice(&(self.memfd_flags.is_some() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.memfd_flags.unwrap_or(0).to_be_bytes());
+        bytes.extend_from_slice(&self.strategy.encode());
+        bytes
+    }
+
+    /// The inverse of `encode`.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let (header, strategy) = bytes.split_at(8);
+        let has_flags = u32::from_be_bytes(header[..4].try_into().unwrap()) != 0;
+        let flags = u32::from_be_bytes(header[4..].try_into().unwrap());
+        Some(Self {
+            strategy: LaunchStrategy::decode(strategy)?,
+            memfd_flags: has_flags.then_some(flags),
+        })
+    }
+}
@@ -0,0 +1,213 @@
+//! Naming, creating and cleaning up the tmpfiles a program is written to when it is launched
+//! from disk instead of from memory.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, DirBuilder, File, Permissions},
+    io::{Error, ErrorKind, Result},
+    os::unix::{
+        fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+        prelude::OsStrExt,
+    },
+    path::{Component, Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use nix::{
+    sys::wait::waitpid,
+    unistd::{fork, setsid, ForkResult},
+};
+
+use crate::anon_pipe::{anon_pipe, AnonPipe};
+
+/// The default tmpfile name template, see `MemFdExecutable::tmpfile_name`.
+pub(crate) const DEFAULT_TEMPLATE: &str = "mfd{uid}{pid}-{rand}/{name}";
+
+/// How tmpfiles are named and which permissions they get.
+#[derive(Debug, Clone)]
+pub(crate) struct TmpFileOptions {
+    pub(crate) template: String,
+    pub(crate) mode: u32,
+}
+
+impl Default for TmpFileOptions {
+    fn default() -> Self {
+        Self {
+            template: DEFAULT_TEMPLATE.to_string(),
+            mode: 0o700,
+        }
+    }
+}
+
+impl TmpFileOptions {
+    /// Expand the template into a path relative to the tmp dir. `{name}` is replaced last so
+    /// placeholders in the program name are left alone.
+    fn render(&self, name: &str) -> Result<PathBuf> {
+        let name = Path::new(name)
+            .file_name()
+            .unwrap_or(OsStr::new("program"))
+            .to_string_lossy();
+        let rel = PathBuf::from(
+            self.template
+                .replace("{uid}", &unsafe { libc::getuid() }.to_string())
+                .replace("{pid}", &process::id().to_string())
+                .replace("{rand}", &format!("{:08x}", random()))
+                .replace("{name}", &name),
+        );
+        if rel.as_os_str().is_empty()
+            || !rel.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the tmpfile name template must expand to a relative path",
+            ));
+        }
+        Ok(rel)
+    }
+}
+
+fn random() -> u32 {
+    let mut buf = [0u8; 4];
+    let n = unsafe {
+        libc::getrandom(
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            libc::GRND_NONBLOCK,
+        )
+    };
+    if n == buf.len() as isize {
+        return u32::from_ne_bytes(buf);
+    }
+    // Good enough to avoid collisions, the file is created with O_EXCL anyway
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    nanos ^ process::id().rotate_left(16)
+}
+
+/// A tmpfile holding a program. `dir` is the tmp dir it was created in and `rel` the path
+/// relative to it, so cleaning up knows which directories are ours to remove.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TmpFile {
+    dir: PathBuf,
+    rel: PathBuf,
+}
+
+impl TmpFile {
+    /// Create a new, empty tmpfile in `dir`, named after the template in `options`. The file
+    /// is never an existing one: if the name is taken, creating it fails.
+    pub(crate) fn create(dir: &Path, options: &TmpFileOptions, name: &str) -> Result<(Self, File)> {
+        let tmpfile = Self {
+            dir: dir.to_path_buf(),
+            rel: options.render(name)?,
+        };
+        let mut created = Vec::new();
+        match tmpfile.try_create(options.mode, &mut created) {
+            Ok(file) => Ok((tmpfile, file)),
+            Err(err) => {
+                // Only undo what we did, the name may be taken by somebody else's file
+                for dir in created.iter().rev() {
+                    let _ = fs::remove_dir(dir);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Create the directories of the template and the file, recording in `created` the
+    /// directories which didn't exist yet. The file is removed again if it can't be set up.
+    fn try_create(&self, mode: u32, created: &mut Vec<PathBuf>) -> Result<File> {
+        let mut dir = self.dir.clone();
+        for component in self.rel.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            match DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => created.push(dir.clone()),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let path = self.path();
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&path)?;
+        // The mode passed to open is masked with the umask
+        if let Err(err) = file.set_permissions(Permissions::from_mode(mode)) {
+            let _ = fs::remove_file(&path);
+            return Err(err);
+        }
+        Ok(file)
+    }
+
+    pub(crate) fn path(&self) -> PathBuf {
+        self.dir.join(&self.rel)
+    }
+
+    /// Remove the file and then every directory the template created for it, stopping at the
+    /// first one which isn't empty. The tmp dir itself is never removed.
+    pub(crate) fn remove(&self) {
+        let _ = fs::remove_file(self.path());
+        let mut parent = self.rel.parent();
+        while let Some(rel) = parent.filter(|rel| !rel.as_os_str().is_empty()) {
+            if fs::remove_dir(self.dir.join(rel)).is_err() {
+                break;
+            }
+            parent = rel.parent();
+        }
+    }
+
+    /// Remove the tmpfile as soon as the current process execs or dies. A detached cleaner
+    /// process waits for the returned CLOEXEC pipe to be closed, which is how it learns the
+    /// kernel is done with the file. Dropping the pipe triggers the cleanup right away.
+    pub(crate) fn remove_on_exec(&self) -> Result<AnonPipe> {
+        let (input, output) = anon_pipe()?;
+        match unsafe { fork() }? {
+            ForkResult::Parent { child } => {
+                drop(input);
+                waitpid(child, None)?;
+                Ok(output)
+            }
+            ForkResult::Child => {
+                // Fork again so the cleaner is not a child of the program we are about to
+                // exec, which would not expect it
+                drop(output);
+                let _ = setsid();
+                if let Ok(ForkResult::Child) = unsafe { fork() } {
+                    let mut buf = [0u8; 1];
+                    loop {
+                        match input.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(_) => {}
+                            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                            Err(_) => break,
+                        }
+                    }
+                    self.remove();
+                }
+                unsafe { libc::_exit(0) }
+            }
+        }
+    }
+
+    /// Serialize the tmpfile as the tmp dir and the relative path separated by a NUL byte,
+    /// which can't appear in either of them.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.dir.as_os_str().as_bytes().to_vec();
+        bytes.push(0);
+        bytes.extend_from_slice(self.rel.as_os_str().as_bytes());
+        bytes
+    }
+
+    /// The inverse of `encode`.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let nul = bytes.iter().position(|&b| b == 0)?;
+        Some(Self {
+            dir: PathBuf::from(OsStr::from_bytes(&bytes[..nul])),
+            rel: PathBuf::from(OsStr::from_bytes(&bytes[nul + 1..])),
+        })
+    }
+}
@@ -0,0 +1,353 @@
+//! Run a static program without `execve`: map its segments into the (forked) current process,
+//! build the stack the kernel would have built and jump to its entry point. This works where
+//! memfds aren't executable and every writable mount is `noexec`, because nothing is ever
+//! executed from a file.
+
+use std::{
+    env::consts::ARCH,
+    ffi::CStr,
+    fs::read_dir,
+    io::{Error, Result},
+    mem::{size_of, zeroed},
+    ptr::{copy_nonoverlapping, null, null_mut},
+};
+
+use crate::elf::{ElfInfo, ElfType};
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// `AT_MINSIGSTKSZ`, which is not in libc
+const AT_MINSIGSTKSZ: libc::c_ulong = 51;
+
+/// The size of the stack of the program. It doesn't grow like the one `execve` sets up.
+const STACK_SIZE: usize = 8 << 20;
+
+/// Map the static program `code` into the current process and run it with `argv` and `envp`,
+/// like `execve` would. Descriptors which are closed on exec are closed and the signal
+/// handlers are reset, but the rest of the address space of the current process stays
+/// mapped, and `/proc/self/exe` still names the current program.
+///
+/// Only returns if the program can't be run this way: `ENOEXEC` if it isn't a static (or
+/// static-PIE) program for this very architecture, or the error of mapping it.
+pub(crate) unsafe fn exec(code: &[u8], argv: &[&CStr], envp: &[&CStr]) -> Result<()> {
+    let info = ElfInfo::parse(code).map_err(|_| Error::from_raw_os_error(libc::ENOEXEC))?;
+    if !info.is_same_arch()
+        || !info.is_static()
+        || matches!(info.elf_type, ElfType::Other(_))
+        || !cfg!(any(target_arch = "x86_64", target_arch = "aarch64"))
+    {
+        return Err(Error::from_raw_os_error(libc::ENOEXEC));
+    }
+
+    let (entry, phdr) = map_segments(code, &info)?;
+    let sp = build_stack(&info, entry, phdr, argv, envp)?;
+
+    // Past this point nothing can fail, so do what the exec would have done
+    close_cloexec_fds();
+    reset_signals();
+    if let Some(name) = argv.first() {
+        let name = name.to_bytes();
+        let name = &name[name.iter().rposition(|&b| b == b'/').map_or(0, |i| i + 1)..];
+        let mut comm = [0u8; 16];
+        comm[..name.len().min(15)].copy_from_slice(&name[..name.len().min(15)]);
+        libc::prctl(libc::PR_SET_NAME, comm.as_ptr());
+    }
+    jump(entry, sp)
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn os_err(errno: i32) -> Error {
+    Error::from_raw_os_error(errno)
+}
+
+/// `ENOEXEC` for an address computation on the headers of the program which overflowed.
+fn noexec<T>(value: Option<T>) -> Result<T> {
+    value.ok_or_else(|| os_err(libc::ENOEXEC))
+}
+
+/// Map the `PT_LOAD` segments, returning the addresses of the entry point and the program
+/// headers where they were mapped. The headers come from the caller, so every address
+/// computed from them is checked: a segment, entry point or header table which doesn't fit is
+/// `ENOEXEC` rather than an overflow.
+unsafe fn map_segments(code: &[u8], info: &ElfInfo) -> Result<(usize, usize)> {
+    let page = page_size();
+    let segments = info.segments();
+    let mut lo = u64::MAX;
+    let mut hi = 0;
+    for segment in segments {
+        let end = noexec(segment.vaddr.checked_add(segment.memsz))?;
+        let file_end = noexec(segment.offset.checked_add(segment.filesz))?;
+        if file_end > code.len() as u64 || segment.filesz > segment.memsz {
+            return Err(os_err(libc::ENOEXEC));
+        }
+        lo = lo.min(segment.vaddr);
+        hi = hi.max(end);
+    }
+    if hi <= lo {
+        return Err(os_err(libc::ENOEXEC));
+    }
+    let lo = lo & !(page as u64 - 1);
+    let span = noexec(usize::try_from(hi - lo).ok())?;
+    let span = noexec(span.checked_next_multiple_of(page))?;
+    // Where an address the program asked for ends up, as an offset into the mapping
+    let offset_of = |vaddr: u64| -> Result<usize> {
+        noexec(vaddr.checked_sub(lo).and_then(|o| o.try_into().ok()))
+    };
+
+    let entry = offset_of(info.entry)?;
+    if entry >= span {
+        return Err(os_err(libc::ENOEXEC));
+    }
+    // The program headers are somewhere in the first segment
+    let (phoff, phnum, phentsize) = info.program_headers();
+    let phend = noexec(phoff.checked_add(phnum as u64 * phentsize as u64))?;
+    let phdr = segments
+        .iter()
+        .find(|s| s.offset <= phoff && phend <= s.offset + s.filesz)
+        .ok_or_else(|| os_err(libc::ENOEXEC))?;
+    let phdr = offset_of(phdr.vaddr + (phoff - phdr.offset))?;
+
+    // Position independent programs go wherever there is room, the others need their address
+    // to be free in our address space
+    let (hint, fixed) = match info.elf_type {
+        ElfType::Dynamic => (null_mut(), 0),
+        _ => (
+            noexec(usize::try_from(lo).ok())? as *mut libc::c_void,
+            libc::MAP_FIXED_NOREPLACE,
+        ),
+    };
+    let region = libc::mmap(
+        hint,
+        span,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | fixed,
+        -1,
+        0,
+    );
+    if region == libc::MAP_FAILED {
+        return Err(Error::last_os_error());
+    }
+    if fixed != 0 && region != hint {
+        // Kernels before 4.17 take MAP_FIXED_NOREPLACE as a mere hint
+        libc::munmap(region, span);
+        return Err(os_err(libc::EEXIST));
+    }
+    // Every segment lies within the span which was just mapped, so from here on the offsets
+    // can't take an address out of it
+    let base = region as usize;
+
+    for segment in segments {
+        let dest = (base + offset_of(segment.vaddr)?) as *mut u8;
+        let offset = segment.offset as usize;
+        let src = &code[offset..offset + segment.filesz as usize];
+        copy_nonoverlapping(src.as_ptr(), dest, src.len());
+    }
+    for segment in segments {
+        let start = (base + offset_of(segment.vaddr)?) & !(page - 1);
+        let end = (base + offset_of(segment.vaddr + segment.memsz)?).next_multiple_of(page);
+        let mut prot = libc::PROT_NONE;
+        if segment.flags & PF_R != 0 {
+            prot |= libc::PROT_READ;
+        }
+        if segment.flags & PF_W != 0 {
+            prot |= libc::PROT_WRITE;
+        }
+        if segment.flags & PF_X != 0 {
+            prot |= libc::PROT_EXEC;
+        }
+        if libc::mprotect(start as *mut libc::c_void, end - start, prot) != 0 {
+            let err = Error::last_os_error();
+            libc::munmap(region, span);
+            return Err(err);
+        }
+    }
+
+    Ok((base + entry, base + phdr))
+}
+
+/// Build the initial stack of the program: the argument and environment strings at the top,
+/// then the auxiliary vector, the environment and argument pointers and the argument count,
+/// which the stack pointer points to.
+unsafe fn build_stack(
+    info: &ElfInfo,
+    entry: usize,
+    phdr: usize,
+    argv: &[&CStr],
+    envp: &[&CStr],
+) -> Result<usize> {
+    let stack = libc::mmap(
+        null_mut(),
+        STACK_SIZE,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+        -1,
+        0,
+    );
+    if stack == libc::MAP_FAILED {
+        return Err(Error::last_os_error());
+    }
+    let mut sp = stack as usize + STACK_SIZE;
+    let mut push = |bytes: &[u8]| {
+        sp -= bytes.len();
+        copy_nonoverlapping(bytes.as_ptr(), sp as *mut u8, bytes.len());
+        sp
+    };
+
+    let random = push(&random_bytes());
+    let platform = push(&[ARCH.as_bytes(), &[0]].concat());
+    let argv = argv
+        .iter()
+        .map(|arg| push(arg.to_bytes_with_nul()))
+        .collect::<Vec<_>>();
+    let envp = envp
+        .iter()
+        .map(|var| push(var.to_bytes_with_nul()))
+        .collect::<Vec<_>>();
+
+    let (_, phnum, phentsize) = info.program_headers();
+    let mut auxv = vec![
+        (libc::AT_PHDR, phdr),
+        (libc::AT_PHENT, phentsize as usize),
+        (libc::AT_PHNUM, phnum as usize),
+        (libc::AT_PAGESZ, page_size()),
+        (libc::AT_BASE, 0),
+        (libc::AT_FLAGS, 0),
+        (libc::AT_ENTRY, entry),
+        (libc::AT_UID, libc::getuid() as usize),
+        (libc::AT_EUID, libc::geteuid() as usize),
+        (libc::AT_GID, libc::getgid() as usize),
+        (libc::AT_EGID, libc::getegid() as usize),
+        (libc::AT_SECURE, 0),
+        (libc::AT_RANDOM, random),
+        (libc::AT_PLATFORM, platform),
+    ];
+    if let Some(&execfn) = argv.first() {
+        auxv.push((libc::AT_EXECFN, execfn));
+    }
+    // The vDSO is inherited from us, and so is what the kernel told us about the CPU
+    for key in [
+        libc::AT_SYSINFO_EHDR,
+        libc::AT_HWCAP,
+        libc::AT_HWCAP2,
+        libc::AT_CLKTCK,
+        AT_MINSIGSTKSZ,
+    ] {
+        let value = libc::getauxval(key);
+        if value != 0 {
+            auxv.push((key, value as usize));
+        }
+    }
+    auxv.push((libc::AT_NULL, 0));
+
+    let words = [argv.len()]
+        .into_iter()
+        .chain(argv)
+        .chain([0])
+        .chain(envp)
+        .chain([0])
+        .chain(
+            auxv.into_iter()
+                .flat_map(|(key, value)| [key as usize, value]),
+        )
+        .collect::<Vec<_>>();
+    // The ABIs want the stack pointer 16 byte aligned at the entry point
+    sp = (sp - words.len() * size_of::<usize>()) & !15;
+    copy_nonoverlapping(words.as_ptr(), sp as *mut usize, words.len());
+    Ok(sp)
+}
+
+/// The 16 random bytes `AT_RANDOM` points to, which libc seeds the stack protector with.
+fn random_bytes() -> [u8; 16] {
+    let mut buf = [0u8; 16];
+    let n = unsafe { libc::getrandom(buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+    if n != buf.len() as isize {
+        // Fall back to the bytes the kernel gave us
+        let ours = unsafe { libc::getauxval(libc::AT_RANDOM) } as *const u8;
+        if !ours.is_null() {
+            unsafe { copy_nonoverlapping(ours, buf.as_mut_ptr(), buf.len()) };
+        }
+    }
+    buf
+}
+
+/// Close the descriptors which are closed on exec. That includes the CLOEXEC pipe, which is
+/// how the parent learns the program has been launched.
+fn close_cloexec_fds() {
+    let fds = match read_dir("/proc/self/fd") {
+        Ok(dir) => dir
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+            .collect::<Vec<libc::c_int>>(),
+        Err(_) => {
+            (0..unsafe { libc::sysconf(libc::_SC_OPEN_MAX) }.clamp(0, 65536) as i32).collect()
+        }
+    };
+    for fd in fds {
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags >= 0 && flags & libc::FD_CLOEXEC != 0 {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// Reset the signals we handle to their default disposition, keeping the ignored ones
+/// ignored, and drop our alternate signal stack.
+unsafe fn reset_signals() {
+    for signal in 1..libc::SIGRTMAX() {
+        let mut action: libc::sigaction = zeroed();
+        if libc::sigaction(signal, null(), &mut action) != 0
+            || action.sa_sigaction == libc::SIG_DFL
+            || action.sa_sigaction == libc::SIG_IGN
+        {
+            continue;
+        }
+        action.sa_sigaction = libc::SIG_DFL;
+        action.sa_flags = 0;
+        libc::sigaction(signal, &action, null_mut());
+    }
+    let disable = libc::stack_t {
+        ss_sp: null_mut(),
+        ss_flags: libc::SS_DISABLE,
+        ss_size: 0,
+    };
+    libc::sigaltstack(&disable, null_mut());
+}
+
+/// Switch to the new stack and jump to the entry point, with the registers the ABI expects:
+/// no function for the program to register with `atexit` and no frame to return to.
+#[cfg(target_arch = "x86_64")]
+unsafe fn jump(entry: usize, sp: usize) -> ! {
+    std::arch::asm!(
+        "mov rsp, rsi",
+        "xor edx, edx",
+        "xor ebp, ebp",
+        "jmp rdi",
+        in("rdi") entry,
+        in("rsi") sp,
+        options(noreturn)
+    )
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn jump(entry: usize, sp: usize) -> ! {
+    std::arch::asm!(
+        "mov sp, x1",
+        "mov x0, xzr",
+        "mov x29, xzr",
+        "mov x30, xzr",
+        "br x2",
+        in("x1") sp,
+        in("x2") entry,
+        options(noreturn)
+    )
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+unsafe fn jump(_entry: usize, _sp: usize) -> ! {
+    unreachable!("userland exec is only supported on x86_64 and aarch64")
+}
@@ -0,0 +1,88 @@
+//! Test driving children from tokio with the `tokio` feature
+#![cfg(feature = "tokio")]
+
+use std::{fs::read, future::Future};
+
+use serial_test::serial;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime::Builder,
+};
+
+use memfd_exec::{MemFdExecutable, Stdio};
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+#[test]
+#[serial]
+fn test_async_pipes() {
+    block_on(async {
+        let mut child = MemFdExecutable::new("cat", read("/bin/cat").unwrap())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Failed to run cat")
+            .into_async()
+            .unwrap();
+
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(b"Hello, world!\n").await.unwrap();
+        drop(stdin);
+
+        let mut output = Vec::new();
+        let mut stdout = child.stdout.take().unwrap();
+        stdout.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, b"Hello, world!\n");
+
+        let status = child.wait().await.unwrap();
+        assert_eq!(status.code(), Some(0));
+    });
+}
+
+#[test]
+#[serial]
+fn test_async_wait_with_output() {
+    // More than a pipe holds on both streams, so neither can be read to the end first
+    let script = "head -c 200000 /dev/zero; head -c 300000 /dev/zero >&2; exit 4";
+    let output = block_on(async {
+        MemFdExecutable::new("sh", read("/bin/sh").unwrap())
+            .args(["-c", script])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to run sh")
+            .into_async()
+            .unwrap()
+            .wait_with_output()
+            .await
+            .unwrap()
+    });
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(output.stdout.len(), 200000);
+    assert_eq!(output.stderr.len(), 300000);
+}
+
+#[test]
+#[serial]
+fn test_async_kill() {
+    block_on(async {
+        let mut child = MemFdExecutable::new("sleep", read("/bin/sleep").unwrap())
+            .arg("30")
+            .spawn()
+            .expect("Failed to run sleep")
+            .into_async()
+            .unwrap();
+        assert!(child.pidfd().is_some());
+        assert_eq!(child.try_wait().unwrap(), None);
+
+        child.kill().unwrap();
+        let status = child.wait().await.unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+    });
+}
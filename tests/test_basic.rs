@@ -2,18 +2,19 @@
 
 use std::{
     fs::read,
-    io::{Read, Write},
+    io::{ErrorKind, Read, Write},
     net::{SocketAddr, TcpStream},
     path::PathBuf,
     process::{Command, Stdio as ProcessStdio},
     str,
+    sync::{Arc, Mutex},
     thread::{sleep, spawn},
     time::Duration,
 };
 
 use serial_test::serial;
 
-use memfd_exec::{MemFdExecutable, Stdio};
+use memfd_exec::{LaunchEvent, MemFdExecError, MemFdExecutable, Stdio};
 
 const TEST_STATIC_CODE: &[u8] = include_bytes!("./test_static.c");
 const CARGO_TARGET_TMPDIR: &str = env!("CARGO_TARGET_TMPDIR");
@@ -100,6 +101,94 @@ fn test_cat_simple() {
         .expect("Failed to run cat");
 }
 
+#[test]
+fn test_owned_code_send() {
+    fn assert_send_static<T: Send + 'static>(_: &T) {}
+
+    let cat = MemFdExecutable::new("cat", read("/bin/cat").expect("Could not read /bin/cat"));
+    assert_send_static(&cat);
+
+    let handles = (0..4)
+        .map(|_| {
+            let mut cat = cat.clone();
+            spawn(move || {
+                cat.arg("Cargo.toml")
+                    .stdout(Stdio::piped())
+                    .output()
+                    .expect("Failed to run cat")
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        let output = handle.join().expect("Failed to join cat thread");
+        assert_eq!(output.status.code(), Some(0));
+        assert!(output.stdout.starts_with(b"[package]"));
+    }
+}
+
+#[test]
+fn test_exec_error_reported() {
+    // Not an executable format the kernel knows about
+    let garbage = vec![0x42u8; 4096];
+    let err = MemFdExecutable::new("garbage", &garbage)
+        .stderr(Stdio::null())
+        .spawn()
+        .expect_err("Garbage should not be executable");
+    // The errno moved from `raw_os_error()` into the `MemFdExecError`
+    assert_eq!(err.raw_os_error(), None, "{err}");
+    let launch_err = MemFdExecError::from_io(&err).expect("Not a launch error");
+    assert_eq!(launch_err.errno(), Some(libc::ENOEXEC), "{err}");
+    assert!(
+        matches!(launch_err, MemFdExecError::FallbackTmpfile { .. }),
+        "{launch_err:?}"
+    );
+
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let err = MemFdExecutable::new("cat", &cat_contents)
+        .cwd("/this/directory/does/not/exist")
+        .spawn()
+        .expect_err("chdir should fail");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::Chdir(libc::ENOENT))
+    );
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+
+    let err = MemFdExecutable::new("cat", &cat_contents)
+        .arg("nul\0byte")
+        .spawn()
+        .expect_err("NUL in argument should fail");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::NulInArgument)
+    );
+}
+
+#[test]
+fn test_launch_events() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let hook_events = events.clone();
+
+    let garbage = vec![0x42u8; 4096];
+    MemFdExecutable::new("garbage", &garbage)
+        .on_event(move |event| hook_events.lock().unwrap().push(event.clone()))
+        .spawn()
+        .expect_err("Garbage should not be executable");
+
+    let events = events.lock().unwrap();
+    assert_eq!(
+        events.first(),
+        Some(&LaunchEvent::Failed(MemFdExecError::Fexecve(libc::ENOEXEC)))
+    );
+    assert!(
+        events
+            .iter()
+            .any(|event| matches!(event, LaunchEvent::TryingTmpfile { .. })),
+        "{events:?}"
+    );
+}
+
 #[test]
 fn test_cat_stdin() {
     let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
@@ -126,6 +215,25 @@ fn test_cat_stdin() {
     );
 }
 
+#[test]
+#[serial]
+fn test_env_inherited() {
+    std::env::set_var("MEMFD_EXEC_INHERITED", "yes");
+    let inherited = MemFdExecutable::new("sh", read("/bin/sh").unwrap())
+        .args(["-c", "echo $MEMFD_EXEC_INHERITED"])
+        .stdout(Stdio::piped())
+        .output();
+    let cleared = MemFdExecutable::new("sh", read("/bin/sh").unwrap())
+        .args(["-c", "echo ${MEMFD_EXEC_INHERITED:-unset}"])
+        .env_clear()
+        .stdout(Stdio::piped())
+        .output();
+    std::env::remove_var("MEMFD_EXEC_INHERITED");
+
+    assert_eq!(inherited.expect("Failed to run sh").stdout, b"yes\n");
+    assert_eq!(cleared.expect("Failed to run sh").stdout, b"unset\n");
+}
+
 #[test]
 #[serial]
 fn test_static_included() {
@@ -0,0 +1,147 @@
+//! Test running dynamically linked programs with their loader and libraries from memory
+
+use std::{
+    fs::read,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio as ProcessStdio},
+    str,
+    thread::spawn,
+};
+
+use serial_test::serial;
+use tempfile::tempdir;
+
+use memfd_exec::{ElfInfo, LaunchStrategy, MemFdExecutable, Stdio};
+
+const TEST_BUNDLE_LIB_CODE: &[u8] = include_bytes!("./test_bundle_lib.c");
+const TEST_BUNDLE_MAIN_CODE: &[u8] = include_bytes!("./test_bundle_main.c");
+const CARGO_TARGET_TMPDIR: &str = env!("CARGO_TARGET_TMPDIR");
+const SONAME: &str = "libmfdbundle.so";
+
+fn clang(code: &'static [u8], args: &[&str]) {
+    let mut clang = Command::new("clang")
+        .args(["-x", "c", "-"])
+        .args(args)
+        .current_dir(CARGO_TARGET_TMPDIR)
+        .stdin(ProcessStdio::piped())
+        .stdout(ProcessStdio::piped())
+        .stderr(ProcessStdio::piped())
+        .spawn()
+        .expect("Failed to run clang");
+
+    let mut clang_stdin = clang.stdin.take().expect("Failed to open stdin");
+    spawn(move || {
+        clang_stdin
+            .write_all(code)
+            .expect("Could not write to clang stdin");
+    });
+
+    let output = clang.wait_with_output().expect("Failed to run clang");
+    assert!(
+        output.status.success(),
+        "clang failed: {}",
+        str::from_utf8(&output.stderr).unwrap()
+    );
+}
+
+/// Build a program linked against a library which is nowhere the loader would look for it,
+/// returning the program and the library.
+fn build_test_bundle() -> (Vec<u8>, Vec<u8>) {
+    let dir = PathBuf::from(CARGO_TARGET_TMPDIR).join("bundle");
+    std::fs::create_dir_all(&dir).unwrap();
+    let lib = dir.join(SONAME);
+    let main = dir.join("test_bundle.bin");
+    clang(
+        TEST_BUNDLE_LIB_CODE,
+        &[
+            "-shared",
+            "-fPIC",
+            &format!("-Wl,-soname,{SONAME}"),
+            "-o",
+            lib.to_str().unwrap(),
+        ],
+    );
+    clang(
+        TEST_BUNDLE_MAIN_CODE,
+        &[
+            "-o",
+            main.to_str().unwrap(),
+            "-L",
+            dir.to_str().unwrap(),
+            "-lmfdbundle",
+        ],
+    );
+    (read(main).unwrap(), read(lib).unwrap())
+}
+
+fn expected(args: &[&str]) -> String {
+    let mut expected = "app: hello from a bundled library\n".to_string();
+    for arg in args {
+        expected.push_str(arg);
+        expected.push('\n');
+    }
+    expected
+}
+
+#[test]
+#[serial]
+fn test_bundle_library() {
+    let (main, lib) = build_test_bundle();
+
+    // The library isn't installed anywhere
+    let status = MemFdExecutable::new("app", &main)
+        .stderr(Stdio::null())
+        .status()
+        .expect("Failed to run the program");
+    assert_eq!(status.code(), Some(127));
+
+    let dir = tempdir().unwrap();
+    for strategy in [
+        LaunchStrategy::Memfd,
+        LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()]),
+    ] {
+        let output = MemFdExecutable::new("app", &main)
+            .library(SONAME, &lib)
+            .args(["one", "two words"])
+            .strategies([strategy.clone()])
+            .stdout(Stdio::piped())
+            .output()
+            .unwrap_or_else(|e| panic!("Failed to run the bundle with {strategy:?}: {e}"));
+        assert_eq!(output.status.code(), Some(0), "{strategy:?}");
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap(),
+            expected(&["one", "two words"])
+        );
+    }
+}
+
+#[test]
+#[serial]
+fn test_bundle_loader() {
+    let (mut main, lib) = build_test_bundle();
+    let interpreter = ElfInfo::parse(&main).unwrap().interpreter.unwrap();
+    let loader = read(&interpreter).unwrap();
+
+    // Point the program at a loader which doesn't exist, the bundled one is used instead
+    let path = interpreter.to_str().unwrap().as_bytes();
+    let at = main
+        .windows(path.len())
+        .position(|window| window == path)
+        .unwrap();
+    let slash = path.iter().rposition(|&b| b == b'/').unwrap();
+    main[at + slash - 1] = b'~';
+    let missing = ElfInfo::parse(&main).unwrap().interpreter.unwrap();
+    assert!(!Path::new(&missing).exists());
+
+    let output = MemFdExecutable::new("app", &main)
+        .loader(&loader)
+        .library(SONAME, &lib)
+        .arg("arg")
+        .check_elf(true)
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run the bundle");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(str::from_utf8(&output.stdout).unwrap(), expected(&["arg"]));
+}
@@ -0,0 +1,3 @@
+#include <stdio.h>
+
+const char *bundled_greeting(void) { return "hello from a bundled library"; }
@@ -0,0 +1,11 @@
+#include <stdio.h>
+
+const char *bundled_greeting(void);
+
+int main(int argc, char **argv) {
+  printf("%s: %s\n", argv[0], bundled_greeting());
+  for (int i = 1; i < argc; i++) {
+    printf("%s\n", argv[i]);
+  }
+  return 0;
+}
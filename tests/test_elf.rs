@@ -0,0 +1,158 @@
+//! Test parsing ELF headers and the pre-flight check before forking
+
+use std::{
+    env::consts::ARCH,
+    fs::{read, read_dir},
+    io::ErrorKind,
+};
+
+use memfd_exec::{
+    ElfClass, ElfError, ElfInfo, ElfType, MemFdExecError, MemFdExecutable, MemFdImage,
+};
+
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+
+fn check(code: &[u8]) -> Option<MemFdExecError> {
+    let err = MemFdExecutable::new("prog", code)
+        .check_elf(true)
+        .spawn()
+        .err()?;
+    MemFdExecError::from_io(&err).cloned()
+}
+
+#[test]
+fn test_parse_native() {
+    let code = read("/bin/sh").unwrap();
+    let info = ElfInfo::parse(&code).expect("sh is an ELF file");
+    assert!(info.is_native());
+    assert_eq!(info.machine_name(), ARCH);
+    assert_eq!(
+        info.class,
+        if cfg!(target_pointer_width = "64") {
+            ElfClass::Elf64
+        } else {
+            ElfClass::Elf32
+        }
+    );
+    assert!(matches!(
+        info.elf_type,
+        ElfType::Executable | ElfType::Dynamic
+    ));
+    if let Some(ref interpreter) = info.interpreter {
+        assert!(interpreter.exists(), "{interpreter:?} should exist");
+    }
+
+    // The same header is found in a memfd image
+    let image = MemFdImage::new("sh", &code).unwrap();
+    assert_eq!(
+        MemFdExecutable::from_image("sh", &image).elf_info(),
+        Ok(info)
+    );
+}
+
+#[test]
+fn test_check_passes() {
+    let status = MemFdExecutable::new("true", read("/bin/true").unwrap())
+        .check_elf(true)
+        .status()
+        .expect("true should pass the check");
+    assert_eq!(status.code(), Some(0));
+
+    // Scripts are left to their interpreter
+    let status = MemFdExecutable::script("true", b"exit 0\n".as_slice())
+        .check_elf(true)
+        .status()
+        .expect("A script should pass the check");
+    assert_eq!(status.code(), Some(0));
+}
+
+#[test]
+fn test_check_truncated() {
+    let code = read("/bin/true").unwrap();
+    assert_eq!(ElfInfo::parse(&code[..40]), Err(ElfError::Truncated));
+    assert_eq!(
+        check(&code[..40]),
+        Some(MemFdExecError::Elf(ElfError::Truncated))
+    );
+    assert_eq!(
+        MemFdExecError::Elf(ElfError::Truncated).to_string(),
+        "cannot run program: truncated ELF"
+    );
+}
+
+#[test]
+fn test_check_not_elf() {
+    assert_eq!(
+        check(&[0x42u8; 4096]),
+        Some(MemFdExecError::Elf(ElfError::NotElf))
+    );
+}
+
+#[test]
+fn test_check_not_executable() {
+    // Turn the program into a relocatable object (ET_REL)
+    let mut code = read("/bin/true").unwrap();
+    code[16..18].copy_from_slice(&1u16.to_ne_bytes());
+    assert_eq!(
+        check(&code),
+        Some(MemFdExecError::Elf(ElfError::NotExecutable(1)))
+    );
+}
+
+#[test]
+fn test_check_foreign_arch() {
+    let foreign = if ARCH == "aarch64" {
+        EM_X86_64
+    } else {
+        EM_AARCH64
+    };
+    let mut code = read("/bin/true").unwrap();
+    code[18..20].copy_from_slice(&foreign.to_ne_bytes());
+    let info = ElfInfo::parse(&code).unwrap();
+    assert!(!info.is_native());
+
+    let err = ElfError::ForeignArch { machine: foreign };
+    assert_eq!(
+        err.to_string(),
+        format!("{} binary on {ARCH} host", info.machine_name())
+    );
+    let qemu = read_dir("/proc/sys/fs/binfmt_misc").is_ok_and(|mut entries| {
+        entries
+            .any(|entry| entry.is_ok_and(|e| e.file_name().to_string_lossy().starts_with("qemu")))
+    });
+    if qemu {
+        // An emulator would run it, so the check lets it through
+        return;
+    }
+    assert_eq!(check(&code), Some(MemFdExecError::Elf(err)));
+}
+
+#[test]
+fn test_check_missing_interpreter() {
+    let mut code = read("/bin/true").unwrap();
+    let Some(interpreter) = ElfInfo::parse(&code).unwrap().interpreter else {
+        // A statically linked system, nothing to break
+        return;
+    };
+    let path = interpreter.to_str().unwrap().as_bytes();
+    let at = code
+        .windows(path.len())
+        .position(|window| window == path)
+        .unwrap();
+    // Rename the last directory component, keeping the length
+    let slash = path.iter().rposition(|&b| b == b'/').unwrap();
+    code[at + slash - 1] = b'~';
+    let missing = ElfInfo::parse(&code).unwrap().interpreter.unwrap();
+    assert!(!missing.exists());
+
+    let err = MemFdExecutable::new("true", &code)
+        .check_elf(true)
+        .spawn()
+        .expect_err("The interpreter should be missing");
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::Elf(ElfError::MissingInterpreter(missing)))
+    );
+}
@@ -0,0 +1,78 @@
+//! Test running foreign architecture programs through an embedded emulator
+
+use std::{env::consts::ARCH, fs::read, str};
+
+use serial_test::serial;
+use tempfile::tempdir;
+
+use memfd_exec::{LaunchStrategy, MemFdExecutable, MemFdImage, Stdio};
+
+/// Stands in for qemu-user: prints what it was started with and the size of the guest
+const EMULATOR: &[u8] = b"#!/bin/sh
+[ \"$1\" = -0 ] || exit 3
+printf '%s\\n' \"$2\" \"$(wc -c < \"$3\")\"
+shift 3
+printf '%s\\n' \"$@\" \"FOO=$FOO\"
+";
+
+/// `/bin/true` claiming to be built for another architecture
+fn foreign_true() -> Vec<u8> {
+    let machine: u16 = if ARCH == "aarch64" { 62 } else { 183 };
+    let mut code = read("/bin/true").unwrap();
+    code[18..20].copy_from_slice(&machine.to_ne_bytes());
+    code
+}
+
+#[test]
+#[serial]
+fn test_emulator_runs_foreign_program() {
+    let code = foreign_true();
+    let dir = tempdir().unwrap();
+    for strategy in [
+        LaunchStrategy::Memfd,
+        LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()]),
+        LaunchStrategy::OTmpFile(vec![dir.path().to_path_buf()]),
+    ] {
+        let output = MemFdExecutable::new("guest", &code)
+            .emulator(EMULATOR)
+            .args(["one", "two words"])
+            .env("FOO", "bar")
+            .strategies([strategy.clone()])
+            .stdout(Stdio::piped())
+            .output()
+            .unwrap_or_else(|e| panic!("Failed to emulate with {strategy:?}: {e}"));
+        assert_eq!(output.status.code(), Some(0), "{strategy:?}");
+        assert_eq!(
+            str::from_utf8(&output.stdout).unwrap(),
+            format!("guest\n{}\none\ntwo words\nFOO=bar\n", code.len()),
+            "{strategy:?}"
+        );
+    }
+}
+
+#[test]
+#[serial]
+fn test_emulator_image() {
+    let code = foreign_true();
+    let emulator = MemFdImage::new("qemu", EMULATOR).unwrap();
+    let output = MemFdExecutable::new("guest", &code)
+        .emulator(emulator)
+        .check_elf(true)
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to emulate");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(str::from_utf8(&output.stdout)
+        .unwrap()
+        .starts_with("guest\n"));
+}
+
+#[test]
+#[serial]
+fn test_emulator_not_used_for_native_program() {
+    let status = MemFdExecutable::new("true", read("/bin/true").unwrap())
+        .emulator(b"#!/bin/sh\nexit 3\n")
+        .status()
+        .expect("Failed to run true");
+    assert_eq!(status.code(), Some(0));
+}
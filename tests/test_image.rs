@@ -0,0 +1,90 @@
+//! Test spawning many children from a single sealed `MemFdImage`
+
+use std::{
+    fs::{read, File, OpenOptions},
+    io::{Read, Result, Write},
+    os::fd::AsRawFd,
+    thread::spawn,
+};
+
+use memfd_exec::{MemFdExecutable, MemFdImage, Stdio};
+
+#[test]
+fn test_image_many_children() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let image = MemFdImage::new("cat", &cat_contents).expect("Failed to create image");
+    assert_eq!(image.len(), cat_contents.len());
+
+    for i in 0..16 {
+        let mut cat = MemFdExecutable::from_image("cat", &image)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Failed to run cat");
+
+        let mut stdin = cat.stdin.take().expect("Failed to open stdin");
+        let message = format!("Hello, world {}!", i);
+        let expected = message.clone();
+        spawn(move || {
+            stdin
+                .write_all(message.as_bytes())
+                .expect("Failed to write to cat stdin");
+        });
+
+        let output = cat.wait_with_output().expect("Failed to run cat");
+        assert_eq!(output.stdout, expected.as_bytes());
+    }
+}
+
+#[test]
+fn test_image_is_sealed() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+    let image = MemFdImage::new("cat", &cat_contents).expect("Failed to create image");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(format!("/proc/self/fd/{}", image.as_raw_fd()))
+        .expect("Failed to reopen image");
+    assert!(file.write_all(b"nope").is_err());
+}
+
+#[test]
+fn test_from_reader() {
+    let cat_contents = read("/bin/cat").expect("Could not read /bin/cat");
+
+    // Feed the code through a reader that only hands out a few bytes at a time
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    let image =
+        MemFdImage::from_reader("cat", Trickle(&cat_contents)).expect("Failed to stream image");
+    assert_eq!(image.len(), cat_contents.len());
+
+    let output = MemFdExecutable::from_image("cat", &image)
+        .arg("Cargo.toml")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run cat");
+    assert!(output.stdout.starts_with(b"[package]"));
+
+    let file = File::open("/bin/cat").expect("Could not open /bin/cat");
+    let len = file.metadata().expect("Could not stat /bin/cat").len();
+    let image = MemFdImage::from_reader_with_len("cat", file, len).expect("Failed to stream image");
+    assert_eq!(image.len() as u64, len);
+
+    let output = MemFdExecutable::from_reader("cat", &cat_contents[..])
+        .expect("Failed to stream image")
+        .arg("Cargo.toml")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run cat");
+    assert!(output.stdout.starts_with(b"[package]"));
+}
@@ -0,0 +1,10 @@
+#ifndef PLUGIN_FACTOR
+#define PLUGIN_FACTOR 1
+#endif
+
+int plugin_counter = 0;
+
+int plugin_mul(int a, int b) {
+  plugin_counter++;
+  return a * b * PLUGIN_FACTOR;
+}
@@ -0,0 +1,96 @@
+//! Test loading shared objects into the current process from memory
+
+use std::{
+    fs::{read, read_to_string},
+    io::{ErrorKind, Write},
+    path::PathBuf,
+    process::{Command, Stdio as ProcessStdio},
+    str,
+    thread::spawn,
+};
+
+use memfd_exec::{MemFdExecError, MemFdImage, MemFdLibrary};
+
+const TEST_LIBRARY_CODE: &[u8] = include_bytes!("./test_library.c");
+const CARGO_TARGET_TMPDIR: &str = env!("CARGO_TARGET_TMPDIR");
+
+type Mul = extern "C" fn(i32, i32) -> i32;
+
+fn build_test_library(factor: i32) -> Vec<u8> {
+    let path = PathBuf::from(CARGO_TARGET_TMPDIR).join(format!("test_library{factor}.so"));
+    let mut clang = Command::new("clang")
+        .args(["-x", "c", "-", "-shared", "-fPIC"])
+        .arg(format!("-DPLUGIN_FACTOR={factor}"))
+        .arg("-o")
+        .arg(&path)
+        .stdin(ProcessStdio::piped())
+        .stderr(ProcessStdio::piped())
+        .spawn()
+        .expect("Failed to run clang");
+
+    let mut clang_stdin = clang.stdin.take().expect("Failed to open stdin");
+    spawn(move || {
+        clang_stdin
+            .write_all(TEST_LIBRARY_CODE)
+            .expect("Could not write to clang stdin");
+    });
+
+    let output = clang.wait_with_output().expect("Failed to run clang");
+    assert!(
+        output.status.success(),
+        "clang failed: {}",
+        str::from_utf8(&output.stderr).unwrap()
+    );
+    read(path).unwrap()
+}
+
+fn is_mapped(name: &str) -> bool {
+    read_to_string("/proc/self/maps")
+        .unwrap()
+        .contains(&format!("/memfd:{name} "))
+}
+
+#[test]
+fn test_library_symbols() {
+    let code = build_test_library(1);
+    let library = MemFdLibrary::open("plugin-symbols.so", &code, libc::RTLD_NOW).unwrap();
+    assert!(library.image().is_some());
+    assert!(is_mapped("plugin-symbols.so"));
+
+    let mul = unsafe { library.get::<Mul>("plugin_mul") }.unwrap();
+    let counter = unsafe { library.get::<*mut i32>("plugin_counter") }.unwrap();
+    assert_eq!(mul(6, 7), 42);
+    assert_eq!(unsafe { **counter }, 1);
+
+    let err = unsafe { library.get::<Mul>("plugin_div") }.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(matches!(
+        MemFdExecError::from_io(&err),
+        Some(MemFdExecError::Dlsym(_))
+    ));
+
+    drop(library);
+    assert!(!is_mapped("plugin-symbols.so"));
+}
+
+#[test]
+fn test_library_many() {
+    // Loaded side by side, each library keeps its own symbols
+    let double =
+        MemFdLibrary::open("plugin-double.so", &build_test_library(2), libc::RTLD_NOW).unwrap();
+    let image = MemFdImage::new("plugin-triple.so", &build_test_library(3)).unwrap();
+    let triple = MemFdLibrary::from_image(&image, libc::RTLD_NOW).unwrap();
+    unsafe {
+        assert_eq!(double.get::<Mul>("plugin_mul").unwrap()(1, 1), 2);
+        assert_eq!(triple.get::<Mul>("plugin_mul").unwrap()(1, 1), 3);
+    }
+}
+
+#[test]
+fn test_library_not_a_library() {
+    let err = MemFdLibrary::open("garbage.so", &[0x42u8; 4096], libc::RTLD_NOW).unwrap_err();
+    assert!(matches!(
+        MemFdExecError::from_io(&err),
+        Some(MemFdExecError::Dlopen(_))
+    ));
+}
@@ -0,0 +1,90 @@
+//! Test that children can be tied to the lifetime of their `Child` and of their parent
+
+use std::{fs::read, thread, time::Duration};
+
+use serial_test::serial;
+
+use memfd_exec::{Child, MemFdExecutable};
+
+fn sleep_30(setup: impl FnOnce(&mut MemFdExecutable)) -> Child {
+    let mut exe = MemFdExecutable::new("sleep", read("/bin/sleep").unwrap());
+    exe.arg("30");
+    setup(&mut exe);
+    exe.spawn().expect("Failed to run sleep")
+}
+
+/// Whether `pid` is a process which hasn't exited, zombies don't count
+fn running(pid: u32) -> bool {
+    match read(format!("/proc/{pid}/stat")) {
+        Ok(stat) => {
+            let end = stat.iter().rposition(|&b| b == b')').unwrap();
+            !matches!(stat[end + 2], b'Z' | b'X')
+        }
+        Err(_) => false,
+    }
+}
+
+#[test]
+#[serial]
+fn test_kill_on_drop() {
+    let child = sleep_30(|exe| {
+        exe.kill_on_drop(true);
+    });
+    let pid = child.id();
+    assert!(running(pid));
+    drop(child);
+    // Killed and reaped, so it's gone entirely
+    assert!(read(format!("/proc/{pid}/stat")).is_err());
+}
+
+#[test]
+#[serial]
+fn test_no_kill_on_drop() {
+    let child = sleep_30(|_| {});
+    let pid = child.id();
+    drop(child);
+    assert!(running(pid));
+    unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+    unsafe { libc::waitpid(pid as i32, std::ptr::null_mut(), 0) };
+}
+
+#[test]
+#[serial]
+fn test_kill_on_drop_after_wait() {
+    let mut child = MemFdExecutable::new("true", read("/bin/true").unwrap())
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Failed to run true");
+    assert_eq!(child.wait().unwrap().code(), Some(0));
+    drop(child);
+}
+
+#[test]
+#[serial]
+fn test_parent_death_signal() {
+    // The thread which spawns the child counts as its parent, so the child is killed as soon
+    // as the thread exits
+    let mut child = thread::spawn(|| {
+        sleep_30(|exe| {
+            exe.parent_death_signal(libc::SIGKILL);
+        })
+    })
+    .join()
+    .unwrap();
+    let status = child
+        .wait_timeout(Duration::from_secs(10))
+        .unwrap()
+        .expect("The child should have been killed with its parent");
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+
+    // Spawned from a thread which lives on, it isn't
+    let mut child = sleep_30(|exe| {
+        exe.parent_death_signal(libc::SIGKILL);
+    });
+    assert_eq!(
+        child.wait_timeout(Duration::from_millis(200)).unwrap(),
+        None
+    );
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
@@ -0,0 +1,136 @@
+//! Test waiting for and killing children through their pidfd
+
+use std::{env, fs::read, os::fd::AsRawFd, process::Command};
+
+use serial_test::serial;
+
+use memfd_exec::{MemFdExecutable, Stdio};
+
+/// Poll the pidfd for readability, which it becomes once the child exits
+fn pidfd_ready(fd: i32, timeout: i32) -> bool {
+    let mut pfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let n = unsafe { libc::poll(&mut pfd, 1, timeout) };
+    assert!(n >= 0, "poll failed");
+    n == 1 && pfd.revents & libc::POLLIN != 0
+}
+
+#[test]
+#[serial]
+fn test_pidfd_poll_and_kill() {
+    let mut child = MemFdExecutable::new("sleep", read("/bin/sleep").unwrap())
+        .arg("30")
+        .spawn()
+        .expect("Failed to run sleep");
+    let fd = child.pidfd().expect("pidfds are not supported").as_raw_fd();
+
+    assert!(!pidfd_ready(fd, 0));
+    assert_eq!(child.try_wait().unwrap(), None);
+
+    child.kill().unwrap();
+    assert!(pidfd_ready(fd, 10_000));
+
+    let status = child.wait().unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGKILL));
+    assert_eq!(status.code(), None);
+    assert!(child.kill().is_err());
+}
+
+#[test]
+#[serial]
+fn test_pidfd_exit_status() {
+    let mut child = MemFdExecutable::new("sh", read("/bin/sh").unwrap())
+        .args(["-c", "exit 3"])
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Failed to run sh");
+    let fd = child.pidfd().expect("pidfds are not supported").as_raw_fd();
+    assert!(pidfd_ready(fd, 10_000));
+
+    let status = child.try_wait().unwrap().expect("the child has exited");
+    assert_eq!(status.code(), Some(3));
+    assert_eq!(status.signal(), None);
+    // The status is remembered once the child is reaped
+    assert_eq!(child.wait().unwrap(), status);
+}
+
+/// Make `waitid(P_PIDFD, ...)` fail with `EINVAL` like on 5.3, which has pidfds but can't
+/// wait on them
+fn deny_waitid_pidfd() {
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    let mut filter = [
+        // seccomp_data.nr
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0),
+        jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            libc::SYS_waitid as u32,
+            0,
+            3,
+        ),
+        // The low half of seccomp_data.args[0], the idtype
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 16),
+        jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            libc::P_PIDFD,
+            0,
+            1,
+        ),
+        stmt(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ERRNO | libc::EINVAL as u32,
+        ),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+    ];
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    unsafe {
+        assert_eq!(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0), 0);
+        assert_eq!(
+            libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog),
+            0,
+            "failed to install the seccomp filter"
+        );
+    }
+}
+
+#[test]
+fn test_pidfd_wait_without_p_pidfd() {
+    // The filter can't be removed again, so it is installed in a copy of this test binary
+    if env::var_os("MEMFD_EXEC_DENY_P_PIDFD").is_none() {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "test_pidfd_wait_without_p_pidfd"])
+            .env("MEMFD_EXEC_DENY_P_PIDFD", "1")
+            .status()
+            .unwrap();
+        assert!(status.code() == Some(0), "waiting in the child failed");
+        return;
+    }
+
+    deny_waitid_pidfd();
+    let mut child = MemFdExecutable::new("sh", read("/bin/sh").unwrap())
+        .args(["-c", "read line; exit 3"])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("Failed to run sh");
+    assert!(child.pidfd().is_some(), "pidfds are not supported");
+
+    assert_eq!(child.try_wait().unwrap(), None);
+    drop(child.stdin.take());
+    assert_eq!(child.wait().unwrap().code(), Some(3));
+}
@@ -0,0 +1,6 @@
+#include <unistd.h>
+
+__attribute__((constructor)) static void shim_loaded(void) {
+  static const char msg[] = "shim loaded\n";
+  write(STDOUT_FILENO, msg, sizeof(msg) - 1);
+}
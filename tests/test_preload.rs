@@ -0,0 +1,95 @@
+//! Test preloading shared objects into programs from memory
+
+use std::{
+    fs::read,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio as ProcessStdio},
+    str,
+    thread::spawn,
+};
+
+use serial_test::serial;
+
+use memfd_exec::{MemFdExecutable, Stdio};
+
+const TEST_PRELOAD_CODE: &[u8] = include_bytes!("./test_preload.c");
+const CARGO_TARGET_TMPDIR: &str = env!("CARGO_TARGET_TMPDIR");
+
+fn build_test_preload() -> Vec<u8> {
+    let path = PathBuf::from(CARGO_TARGET_TMPDIR).join("test_preload.so");
+    let mut clang = Command::new("clang")
+        .args(["-x", "c", "-", "-shared", "-fPIC", "-o"])
+        .arg(&path)
+        .stdin(ProcessStdio::piped())
+        .stderr(ProcessStdio::piped())
+        .spawn()
+        .expect("Failed to run clang");
+
+    let mut clang_stdin = clang.stdin.take().expect("Failed to open stdin");
+    spawn(move || {
+        clang_stdin
+            .write_all(TEST_PRELOAD_CODE)
+            .expect("Could not write to clang stdin");
+    });
+
+    let output = clang.wait_with_output().expect("Failed to run clang");
+    assert!(
+        output.status.success(),
+        "clang failed: {}",
+        str::from_utf8(&output.stderr).unwrap()
+    );
+    read(path).unwrap()
+}
+
+#[test]
+#[serial]
+fn test_preload() {
+    let shim = build_test_preload();
+    let output = MemFdExecutable::new("sh", read("/bin/sh").unwrap())
+        .preload("shim.so", &shim)
+        // The glob also matches the descriptor of the directory it reads, which is gone by
+        // the time readlink looks at it
+        .args([
+            "-c",
+            "echo \"$LD_PRELOAD\"; readlink /proc/$$/fd/* 2>/dev/null; exit 0",
+        ])
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run sh");
+    assert_eq!(output.status.code(), Some(0));
+
+    let stdout = str::from_utf8(&output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("shim loaded"));
+    let preload = lines.next().unwrap();
+    assert!(preload.starts_with("/proc/self/fd/"), "{preload}");
+    assert!(!preload.contains(':'), "{preload}");
+    // The shim is only ever in memory
+    assert!(lines.any(|fd| fd.starts_with("/memfd:shim.so")), "{stdout}");
+}
+
+#[test]
+#[serial]
+fn test_preload_merges_ld_preload() {
+    let shim = build_test_preload();
+    let output = MemFdExecutable::new("sh", read("/bin/sh").unwrap())
+        .preload("first.so", &shim)
+        .preload("second.so", &shim)
+        .env("LD_PRELOAD", "libmissing.so")
+        .args(["-c", "echo \"$LD_PRELOAD\""])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .output()
+        .expect("Failed to run sh");
+    assert_eq!(output.status.code(), Some(0));
+
+    let stdout = str::from_utf8(&output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("shim loaded"));
+    assert_eq!(lines.next(), Some("shim loaded"));
+    let preload = lines.next().unwrap().split(':').collect::<Vec<_>>();
+    assert_eq!(preload.len(), 3, "{preload:?}");
+    assert!(preload[..2].iter().all(|p| p.starts_with("/proc/self/fd/")));
+    assert_eq!(preload[2], "libmissing.so");
+}
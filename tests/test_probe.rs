@@ -0,0 +1,127 @@
+//! Test the host capability probe
+
+use std::{
+    env,
+    fs::{read, read_to_string},
+    process::Command,
+};
+
+use memfd_exec::{probe, LaunchStrategy, MemFdExecutable, MemfdNoexec};
+
+#[test]
+fn test_probe_matches_host() {
+    let caps = probe();
+    assert!(caps.memfd_create);
+
+    let expected = match read_to_string("/proc/sys/vm/memfd_noexec").as_deref().map(str::trim) {
+        Ok("0") => MemfdNoexec::Allowed,
+        Ok("1") => MemfdNoexec::NoexecByDefault,
+        Ok("2") => MemfdNoexec::Enforced,
+        _ => MemfdNoexec::Unavailable,
+    };
+    assert_eq!(caps.memfd_noexec, expected);
+    // The sysctl and the flags came with the same kernel release
+    assert_eq!(caps.mfd_exec_flags, expected != MemfdNoexec::Unavailable);
+
+    let tmp = caps
+        .tmp_dirs
+        .iter()
+        .find(|dir| dir.path == env::temp_dir())
+        .expect("the temp dir should be a candidate");
+    assert!(tmp.exists);
+}
+
+#[test]
+fn test_recommended_strategies() {
+    let caps = probe();
+    let strategies = caps.recommended_strategies();
+    assert_eq!(
+        strategies.first().is_some_and(|s| !s.touches_disk()),
+        caps.can_exec_from_memory()
+    );
+    if let Some(LaunchStrategy::TmpFile(dirs)) = strategies.last() {
+        assert!(dirs.iter().all(|dir| caps
+            .tmp_dirs
+            .iter()
+            .any(|status| &status.path == dir && status.is_usable())));
+    }
+
+    let status = MemFdExecutable::new("true", read("/bin/true").unwrap())
+        .strategies(strategies)
+        .status()
+        .expect("The recommended strategies should work");
+    assert_eq!(status.code(), Some(0));
+}
+
+/// Make `memfd_create` fail with `EACCES` when asked for `MFD_EXEC`, like the kernel does with
+/// `vm.memfd_noexec=2`. The filter only ever lets through more than it should: it doesn't
+/// check the architecture, which is fine for a test process.
+fn deny_mfd_exec() {
+    const MFD_EXEC: u32 = 0x10;
+    let stmt = |code: u32, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code: u32, k: u32, jt: u8, jf: u8| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    let mut filter = [
+        // seccomp_data.nr
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 0),
+        jump(
+            libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+            libc::SYS_memfd_create as u32,
+            0,
+            3,
+        ),
+        // The low half of seccomp_data.args[1], the flags
+        stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, 24),
+        jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, MFD_EXEC, 0, 1),
+        stmt(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ERRNO | libc::EACCES as u32,
+        ),
+        stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+    ];
+    let prog = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    unsafe {
+        assert_eq!(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0), 0);
+        assert_eq!(
+            libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog),
+            0,
+            "failed to install the seccomp filter"
+        );
+    }
+}
+
+#[test]
+fn test_probe_executable_memfds_refused() {
+    // The filter can't be removed again, so it is installed in a copy of this test binary
+    if env::var_os("MEMFD_EXEC_DENY_MFD_EXEC").is_none() {
+        let status = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "test_probe_executable_memfds_refused"])
+            .env("MEMFD_EXEC_DENY_MFD_EXEC", "1")
+            .status()
+            .unwrap();
+        assert!(status.code() == Some(0), "the probe in the child failed");
+        return;
+    }
+
+    deny_mfd_exec();
+    let caps = probe();
+    assert!(caps.memfd_create);
+    assert!(!caps.memfd_executable);
+    assert!(!caps.can_exec_from_memory());
+    assert!(caps
+        .recommended_strategies()
+        .iter()
+        .all(LaunchStrategy::touches_disk));
+}
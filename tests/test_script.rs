@@ -0,0 +1,89 @@
+//! Test running scripts from memory through an interpreter
+
+use std::{io::Write, thread::spawn};
+
+use tempfile::tempdir;
+
+use memfd_exec::{LaunchStrategy, MemFdExecError, MemFdExecutable, Stdio};
+
+#[test]
+fn test_script_without_shebang() {
+    let script = b"read line\necho \"$1: $line\"\n";
+    let mut child = MemFdExecutable::script("greet", script)
+        .arg("got")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run script");
+
+    let mut stdin = child.stdin.take().unwrap();
+    spawn(move || stdin.write_all(b"hello\n").unwrap());
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.stdout, b"got: hello\n");
+    assert_eq!(output.status.code(), Some(0));
+
+    // Without `script` a file without `#!` isn't executable
+    let err = MemFdExecutable::new("greet", script)
+        .strategies([LaunchStrategy::Memfd])
+        .spawn()
+        .expect_err("A script without #! should not be executable");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::Fexecve(libc::ENOEXEC))
+    );
+}
+
+#[test]
+fn test_script_interpreter() {
+    let dir = tempdir().unwrap();
+    for strategy in [
+        LaunchStrategy::Memfd,
+        LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()]),
+        LaunchStrategy::OTmpFile(vec![dir.path().to_path_buf()]),
+    ] {
+        let output = MemFdExecutable::script("count", b"{ n += $1 } END { print n }")
+            .interpreter("/usr/bin/awk")
+            .interpreter_args(["-f"])
+            .strategies([strategy.clone()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().unwrap().write_all(b"1\n2\n3\n")?;
+                child.wait_with_output()
+            })
+            .unwrap_or_else(|e| panic!("{strategy:?} failed: {e}"));
+        assert_eq!(output.stdout, b"6\n", "{strategy:?}");
+    }
+}
+
+#[test]
+fn test_interpreter_overrides_shebang() {
+    let output = MemFdExecutable::script("hello", b"#!/bin/false\necho hello\n")
+        .interpreter("/bin/sh")
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run script");
+    assert_eq!(output.stdout, b"hello\n");
+}
+
+#[test]
+fn test_interpreter_args_before_interpreter() {
+    // The arguments are kept whether they are given before or after the interpreter, with
+    // or without a `#!` line
+    for script in [&b"#!/bin/false\n{ print $1 }\n"[..], b"{ print $1 }\n"] {
+        let output = MemFdExecutable::script("first", script)
+            .interpreter_args(["-f"])
+            .interpreter("/usr/bin/awk")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                child.stdin.take().unwrap().write_all(b"one two\n")?;
+                child.wait_with_output()
+            })
+            .expect("Failed to run script");
+        assert_eq!(output.stdout, b"one\n");
+    }
+}
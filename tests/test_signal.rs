@@ -0,0 +1,152 @@
+//! Test signalling children and tearing down their process groups and helpers
+
+use std::{
+    fs::read,
+    io::{BufRead, BufReader, ErrorKind},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use serial_test::serial;
+
+use memfd_exec::{Child, MemFdExecError, MemFdExecutable, Stdio};
+
+/// Spawn a shell running `script`, which prints the PIDs of the helpers it starts
+fn sh(script: &str, setup: impl FnOnce(&mut MemFdExecutable)) -> Child {
+    let mut exe = MemFdExecutable::new("sh", read("/bin/sh").unwrap());
+    exe.args(["-c", script]).stdout(Stdio::piped());
+    setup(&mut exe);
+    exe.spawn().expect("Failed to run sh")
+}
+
+/// Read `count` lines, the PIDs the script printed
+fn read_pids(child: &mut Child, count: usize) -> Vec<i32> {
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    (0..count)
+        .map(|_| {
+            let mut line = String::new();
+            stdout.read_line(&mut line).unwrap();
+            line.trim().parse().unwrap()
+        })
+        .collect()
+}
+
+/// The state, process group and session of `pid`
+fn stat(pid: i32) -> Option<(char, i32, i32)> {
+    let stat = String::from_utf8(read(format!("/proc/{pid}/stat")).ok()?).unwrap();
+    let mut fields = stat[stat.rfind(')').unwrap() + 1..].split_whitespace();
+    let state = fields.next()?.chars().next()?;
+    let pgrp = fields.nth(1)?.parse().ok()?;
+    let session = fields.next()?.parse().ok()?;
+    Some((state, pgrp, session))
+}
+
+/// Wait for `pid` to die. Orphans may be left as zombies by whatever reaps them here.
+fn dies(pid: i32) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        match stat(pid) {
+            None | Some(('Z' | 'X', ..)) => return true,
+            Some(_) => sleep(Duration::from_millis(10)),
+        }
+    }
+    false
+}
+
+#[test]
+#[serial]
+fn test_signal() {
+    let mut child = sh(
+        "trap 'exit 9' USR1; echo $$; while :; do sleep 0.1; done",
+        |_| {},
+    );
+    read_pids(&mut child, 1);
+    child.signal(libc::SIGUSR1).unwrap();
+    assert_eq!(child.wait().unwrap().code(), Some(9));
+    assert!(child.signal(libc::SIGUSR1).is_err());
+}
+
+#[test]
+#[serial]
+fn test_process_group_and_session() {
+    let ours = stat(std::process::id() as i32).unwrap();
+
+    let mut child = sh("exec sleep 30", |exe| {
+        exe.process_group(0);
+    });
+    let pid = child.id() as i32;
+    assert_eq!(child.process_group(), Some(pid as u32));
+    let (_, pgrp, session) = stat(pid).unwrap();
+    assert_eq!(pgrp, pid);
+    assert_eq!(session, ours.2);
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let mut child = sh("exec sleep 30", |exe| {
+        exe.setsid(true);
+    });
+    let pid = child.id() as i32;
+    assert_eq!(child.process_group(), Some(pid as u32));
+    let (_, pgrp, session) = stat(pid).unwrap();
+    assert_eq!((pgrp, session), (pid, pid));
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_signal_group() {
+    let mut child = sh("sleep 30 & echo $!; wait", |exe| {
+        exe.process_group(0);
+    });
+    let helper = read_pids(&mut child, 1)[0];
+    assert_eq!(stat(helper).unwrap().1, child.id() as i32);
+
+    child.signal_group(libc::SIGTERM).unwrap();
+    assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+    assert!(dies(helper));
+}
+
+#[test]
+#[serial]
+fn test_signal_group_without_group() {
+    let mut child = sh("exec sleep 30", |_| {});
+    assert_eq!(child.process_group(), None);
+    let err = child.signal_group(libc::SIGKILL).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+#[serial]
+fn test_kill_tree() {
+    // Without a process group of its own, the helpers are found by walking /proc
+    let script = "sh -c 'sleep 30 & echo $!; wait' & echo $!; wait";
+    for group in [false, true] {
+        let mut child = sh(script, |exe| {
+            if group {
+                exe.process_group(0);
+            }
+        });
+        let helpers = read_pids(&mut child, 2);
+        child.kill_tree().unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+        for helper in helpers {
+            assert!(dies(helper), "helper {helper} survived, group: {group}");
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn test_setsid_with_process_group() {
+    let err = MemFdExecutable::new("true", read("/bin/true").unwrap())
+        .setsid(true)
+        .process_group(0)
+        .spawn()
+        .expect_err("A session leader can't be moved into a group");
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    // Refused before forking rather than by the child's setpgid
+    assert!(MemFdExecError::from_io(&err).is_none());
+}
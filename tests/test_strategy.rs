@@ -0,0 +1,215 @@
+//! Test choosing and restricting the launch strategy chain
+
+use std::{
+    env,
+    fs::read,
+    sync::{Arc, Mutex},
+};
+
+use serial_test::serial;
+use tempfile::tempdir;
+
+use memfd_exec::{
+    probe, LaunchEvent, LaunchStrategy, MemFdExecError, MemFdExecutable, MemFdImage, Stdio,
+};
+
+fn collect_events(exe: &mut MemFdExecutable) -> Arc<Mutex<Vec<LaunchEvent>>> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let hook_events = events.clone();
+    exe.on_event(move |event| hook_events.lock().unwrap().push(event.clone()));
+    events
+}
+
+#[test]
+#[serial]
+fn test_each_strategy() {
+    let dir = tempdir().unwrap();
+    let code = read("/bin/echo").unwrap();
+    for strategy in [
+        LaunchStrategy::Memfd,
+        LaunchStrategy::MemfdNoCloexec,
+        LaunchStrategy::ExecveatEmptyPath,
+        LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()]),
+        LaunchStrategy::OTmpFile(vec![dir.path().to_path_buf()]),
+    ] {
+        let output = MemFdExecutable::new("echo", &code)
+            .arg("hello")
+            .strategies([strategy.clone()])
+            .stdout(Stdio::piped())
+            .output()
+            .unwrap_or_else(|e| panic!("{strategy:?} failed: {e}"));
+        assert_eq!(output.stdout, b"hello\n", "{strategy:?}");
+        assert_eq!(output.status.code(), Some(0), "{strategy:?}");
+    }
+}
+
+#[test]
+#[serial]
+fn test_memory_only_never_touches_disk() {
+    let garbage = vec![0x42u8; 4096];
+    let mut exe = MemFdExecutable::new("garbage", &garbage);
+    exe.strategies(LaunchStrategy::memory_only());
+    let events = collect_events(&mut exe);
+
+    let err = exe.spawn().expect_err("Garbage should not be executable");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::Fexecve(libc::ENOEXEC))
+    );
+    let events = events.lock().unwrap();
+    assert!(
+        !events
+            .iter()
+            .any(|event| matches!(event, LaunchEvent::TryingTmpfile { .. })),
+        "{events:?}"
+    );
+    // Every strategy but the last one reports its failure as an event
+    assert_eq!(events.len(), LaunchStrategy::memory_only().len() - 1);
+}
+
+#[test]
+#[serial]
+fn test_no_memfdexec_override() {
+    env::set_var("NO_MEMFDEXEC", "1");
+
+    let code = read("/bin/true").unwrap();
+    let err = MemFdExecutable::new("true", &code)
+        .strategies(LaunchStrategy::memory_only())
+        .spawn()
+        .expect_err("NO_MEMFDEXEC must not add disk strategies");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::NoLaunchStrategy)
+    );
+
+    let dir = tempdir().unwrap();
+    let mut exe = MemFdExecutable::new("true", &code);
+    exe.strategies([
+        LaunchStrategy::Memfd,
+        LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()]),
+    ]);
+    let events = collect_events(&mut exe);
+    let status = exe.status();
+
+    env::remove_var("NO_MEMFDEXEC");
+
+    assert_eq!(status.unwrap().code(), Some(0));
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            LaunchEvent::MemfdDisabled,
+            LaunchEvent::TryingTmpfile {
+                dir: dir.path().to_path_buf()
+            }
+        ]
+    );
+}
+
+#[test]
+#[serial]
+fn test_empty_chain() {
+    let code = read("/bin/true").unwrap();
+    let err = MemFdExecutable::new("true", &code)
+        .strategies([])
+        .spawn()
+        .expect_err("An empty chain cannot launch anything");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::NoLaunchStrategy)
+    );
+}
+
+#[test]
+#[serial]
+fn test_launch_info() {
+    const MFD_CLOEXEC: u32 = 0x1;
+    const MFD_ALLOW_SEALING: u32 = 0x2;
+    const MFD_EXEC: u32 = 0x10;
+
+    let exec_flag = if probe().mfd_exec_flags { MFD_EXEC } else { 0 };
+    let dir = tempdir().unwrap();
+    let code = read("/bin/true").unwrap();
+    let image = MemFdImage::new("true", &code).unwrap();
+
+    for (mut exe, strategy, flags) in [
+        (
+            MemFdExecutable::new("true", &code),
+            LaunchStrategy::Memfd,
+            Some(MFD_CLOEXEC | exec_flag),
+        ),
+        (
+            MemFdExecutable::new("true", &code),
+            LaunchStrategy::MemfdNoCloexec,
+            Some(exec_flag),
+        ),
+        (
+            MemFdExecutable::from_image("true", &image),
+            LaunchStrategy::Memfd,
+            Some(MFD_CLOEXEC | MFD_ALLOW_SEALING | exec_flag),
+        ),
+        (
+            MemFdExecutable::new("true", &code),
+            LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()]),
+            None,
+        ),
+    ] {
+        let mut child = exe.strategies([strategy.clone()]).spawn().unwrap();
+        let info = child.launch_info().expect("the child should report how it launched");
+        assert_eq!(info.strategy, strategy);
+        assert_eq!(info.memfd_flags, flags, "{strategy:?}");
+        assert_eq!(child.wait().unwrap().code(), Some(0));
+    }
+}
+
+#[test]
+#[serial]
+fn test_memfd_cloexec() {
+    // A native program doesn't need the memfd, so it is closed on exec by default
+    let fds = |exe: &mut MemFdExecutable| {
+        let output = exe
+            .arg("-c")
+            .arg("ls /proc/$$/fd")
+            .stdout(Stdio::piped())
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let code = read("/bin/sh").unwrap();
+    assert_eq!(fds(&mut MemFdExecutable::new("sh", &code)), "0\n1\n2\n");
+
+    let mut exe = MemFdExecutable::new("sh", &code);
+    exe.memfd_cloexec(false);
+    assert_eq!(fds(&mut exe).lines().count(), 4);
+}
+
+#[test]
+#[serial]
+fn test_script() {
+    let dir = tempdir().unwrap();
+    let script = b"#!/bin/sh\necho \"hello $1\"\n";
+    for strategy in [
+        LaunchStrategy::Memfd,
+        LaunchStrategy::ExecveatEmptyPath,
+        LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()]),
+        LaunchStrategy::OTmpFile(vec![dir.path().to_path_buf()]),
+    ] {
+        let output = MemFdExecutable::new("hello", script)
+            .arg("world")
+            .strategies([strategy.clone()])
+            .stdout(Stdio::piped())
+            .output()
+            .unwrap_or_else(|e| panic!("{strategy:?} failed: {e}"));
+        assert_eq!(output.stdout, b"hello world\n", "{strategy:?}");
+    }
+
+    // The interpreter can't reopen a script which was closed on exec
+    let err = MemFdExecutable::new("hello", script)
+        .strategies([LaunchStrategy::ExecveatEmptyPath])
+        .memfd_cloexec(true)
+        .spawn()
+        .expect_err("The script should not be found by the interpreter");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::Fexecve(libc::ENOENT))
+    );
+}
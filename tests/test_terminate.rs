@@ -0,0 +1,96 @@
+//! Test waiting with a timeout and terminating children gracefully
+
+use std::{
+    fs::read,
+    io::Read,
+    time::{Duration, Instant},
+};
+
+use serial_test::serial;
+
+use memfd_exec::{Child, MemFdExecutable, Stdio, Termination};
+
+fn sh(script: &str) -> Child {
+    MemFdExecutable::new("sh", read("/bin/sh").unwrap())
+        .args(["-c", script])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to run sh")
+}
+
+/// Wait for the script to say it has set up its traps
+fn wait_ready(child: &mut Child) {
+    let mut ready = [0u8; 6];
+    child
+        .stdout
+        .as_mut()
+        .unwrap()
+        .read_exact(&mut ready)
+        .unwrap();
+    assert_eq!(&ready, b"ready\n");
+}
+
+#[test]
+#[serial]
+fn test_wait_timeout() {
+    let mut child = sh("exec sleep 30");
+    let start = Instant::now();
+    assert_eq!(
+        child.wait_timeout(Duration::from_millis(200)).unwrap(),
+        None
+    );
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let mut child = sh("exit 5");
+    let status = child
+        .wait_timeout(Duration::from_secs(10))
+        .unwrap()
+        .expect("The child should have exited");
+    assert_eq!(status.code(), Some(5));
+}
+
+#[test]
+#[serial]
+fn test_terminate() {
+    let mut child = sh("exec sleep 30");
+    let termination = child.terminate(Duration::from_secs(10)).unwrap();
+    assert!(matches!(termination, Termination::Terminated(_)));
+    assert_eq!(termination.status().signal(), Some(libc::SIGTERM));
+}
+
+#[test]
+#[serial]
+fn test_terminate_with_handler() {
+    let mut child = sh("trap 'exit 7' INT; echo ready; while :; do sleep 0.1; done");
+    wait_ready(&mut child);
+    let termination = child
+        .terminate_with(libc::SIGINT, Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(termination, Termination::Terminated(termination.status()));
+    assert_eq!(termination.status().code(), Some(7));
+}
+
+#[test]
+#[serial]
+fn test_terminate_escalates() {
+    // An ignored SIGTERM stays ignored across the exec
+    let mut child = sh("trap '' TERM; echo ready; exec sleep 30");
+    wait_ready(&mut child);
+    let start = Instant::now();
+    let termination = child.terminate(Duration::from_millis(200)).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(matches!(termination, Termination::Killed(_)));
+    assert_eq!(termination.status().signal(), Some(libc::SIGKILL));
+}
+
+#[test]
+#[serial]
+fn test_terminate_exited() {
+    let mut child = sh("exit 0");
+    child.wait().unwrap();
+    let termination = child.terminate(Duration::from_secs(10)).unwrap();
+    assert!(matches!(termination, Termination::Exited(_)));
+    assert_eq!(termination.status().code(), Some(0));
+}
@@ -0,0 +1,142 @@
+//! Test naming and cleaning up tmpfiles the program is written to
+
+use std::{
+    fs::{create_dir, read, read_dir, write},
+    path::Path,
+    str,
+    thread::sleep,
+    time::Duration,
+};
+
+use nix::{
+    sys::wait::waitpid,
+    unistd::{fork, ForkResult},
+};
+use serial_test::serial;
+use tempfile::tempdir;
+
+use memfd_exec::{LaunchStrategy, MemFdExecError, MemFdExecutable, Stdio};
+
+fn is_empty(dir: &Path) -> bool {
+    read_dir(dir).unwrap().next().is_none()
+}
+
+#[test]
+#[serial]
+fn test_tmpfile_name_and_mode() {
+    let dir = tempdir().unwrap();
+    let output = MemFdExecutable::new("sh", read("/bin/sh").unwrap())
+        .arg("-c")
+        .arg("readlink /proc/$$/exe; stat -L -c %a /proc/$$/exe")
+        .strategies([LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()])])
+        .tmpfile_name("sub/{name}-{pid}")
+        .tmpfile_mode(0o500)
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to run sh from a tmpfile");
+    assert_eq!(output.status.code(), Some(0));
+
+    let stdout = str::from_utf8(&output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    let exe = lines.next().unwrap();
+    let expected = dir.path().join("sub/sh-");
+    assert!(
+        exe.starts_with(expected.to_str().unwrap()),
+        "{exe} is not {expected:?}"
+    );
+    assert_eq!(lines.next(), Some("500"));
+
+    // The parent removed the tmpfile and the directory made for it once the program ran
+    assert!(is_empty(dir.path()));
+}
+
+#[test]
+#[serial]
+fn test_tmpfile_removed_on_failure() {
+    let dir = tempdir().unwrap();
+    let garbage = vec![0x42u8; 4096];
+    let err = MemFdExecutable::new("garbage", &garbage)
+        .strategies([LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()])])
+        .spawn()
+        .expect_err("Garbage should not be executable");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::FallbackTmpfile {
+            dir: dir.path().to_path_buf(),
+            errno: libc::ENOEXEC
+        })
+    );
+    assert!(is_empty(dir.path()));
+}
+
+#[test]
+#[serial]
+fn test_tmpfile_removed_after_exec() {
+    let dir = tempdir().unwrap();
+    let code = read("/bin/true").unwrap();
+    let mut exe = MemFdExecutable::new("true", &code);
+    exe.strategies([LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()])]);
+
+    match unsafe { fork() }.unwrap() {
+        ForkResult::Child => {
+            exe.exec(Stdio::Inherit);
+            unsafe { libc::_exit(1) }
+        }
+        ForkResult::Parent { child } => {
+            waitpid(child, None).unwrap();
+        }
+    }
+
+    // Nobody is left to clean up after `exec` but the detached cleaner process
+    for _ in 0..100 {
+        if is_empty(dir.path()) {
+            return;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    panic!("the tmpfile was left behind");
+}
+
+#[test]
+#[serial]
+fn test_tmpfile_bad_template() {
+    let dir = tempdir().unwrap();
+    let code = read("/bin/true").unwrap();
+    let err = MemFdExecutable::new("true", &code)
+        .strategies([LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()])])
+        .tmpfile_name("../{name}")
+        .spawn()
+        .expect_err("The tmpfile must stay inside the tmp dir");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::FallbackTmpfile {
+            dir: dir.path().to_path_buf(),
+            errno: libc::EINVAL
+        })
+    );
+}
+
+#[test]
+#[serial]
+fn test_tmpfile_existing_name_left_alone() {
+    let dir = tempdir().unwrap();
+    let precious = dir.path().join("keep/precious-true");
+    create_dir(dir.path().join("keep")).unwrap();
+    write(&precious, b"not ours").unwrap();
+
+    let code = read("/bin/true").unwrap();
+    let err = MemFdExecutable::new("true", &code)
+        .strategies([LaunchStrategy::TmpFile(vec![dir.path().to_path_buf()])])
+        .tmpfile_name("keep/precious-{name}")
+        .spawn()
+        .expect_err("The tmpfile name is taken");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::FallbackTmpfile {
+            dir: dir.path().to_path_buf(),
+            errno: libc::EEXIST
+        })
+    );
+    // Neither the file nor its directory were ours to remove
+    assert_eq!(read(&precious).unwrap(), b"not ours");
+}
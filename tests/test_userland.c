@@ -0,0 +1,20 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <sys/auxv.h>
+#include <unistd.h>
+
+/* Print what a freshly executed program should see: its arguments, its environment and the
+ * auxiliary vector values libc relies on. */
+int main(int argc, char **argv) {
+  for (int i = 0; i < argc; i++) {
+    printf("%s\n", argv[i]);
+  }
+  const char *foo = getenv("FOO");
+  printf("FOO=%s\n", foo ? foo : "(unset)");
+  printf("pagesz=%d\n", getauxval(AT_PAGESZ) == (unsigned long)sysconf(_SC_PAGESIZE));
+  printf("random=%d\n", getauxval(AT_RANDOM) != 0);
+  char *heap = malloc(1 << 20);
+  heap[(1 << 20) - 1] = 1;
+  free(heap);
+  return 3;
+}
@@ -0,0 +1,159 @@
+//! Test running static programs by mapping them in userland, without executing any file
+
+use std::{
+    fs::read,
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    process::{Command, Stdio as ProcessStdio},
+    str,
+    thread::spawn,
+};
+
+use serial_test::serial;
+
+use memfd_exec::{LaunchStrategy, MemFdExecError, MemFdExecutable, MemFdImage, Stdio};
+
+const TEST_STATIC_CODE: &[u8] = include_bytes!("./test_static.c");
+const TEST_USERLAND_CODE: &[u8] = include_bytes!("./test_userland.c");
+const CARGO_TARGET_TMPDIR: &str = env!("CARGO_TARGET_TMPDIR");
+
+/// Build a static program, or a static-PIE if `pie`
+fn build_static(code: &'static [u8], pie: bool, name: &str) -> Vec<u8> {
+    let flag = if pie { "-static-pie" } else { "-static" };
+    let path = PathBuf::from(CARGO_TARGET_TMPDIR).join(name);
+    let mut clang = Command::new("clang")
+        .args(["-x", "c", flag, "-o"])
+        .arg(&path)
+        .arg("-")
+        .stdin(ProcessStdio::piped())
+        .stderr(ProcessStdio::piped())
+        .spawn()
+        .expect("Failed to run clang");
+
+    let mut clang_stdin = clang.stdin.take().expect("Failed to open stdin");
+    spawn(move || {
+        clang_stdin
+            .write_all(code)
+            .expect("Could not write to clang stdin");
+    });
+
+    let output = clang.wait_with_output().expect("Failed to run clang");
+    assert!(
+        output.status.success(),
+        "clang failed: {}",
+        str::from_utf8(&output.stderr).unwrap()
+    );
+    read(path).unwrap()
+}
+
+/// Talk to the test program like `test_static_included` does
+fn run_test_static(exe: &mut MemFdExecutable) {
+    let mut child = exe
+        .strategies([LaunchStrategy::Userland])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to map the program");
+    assert_eq!(
+        child.launch_info().map(|info| &info.strategy),
+        Some(&LaunchStrategy::Userland)
+    );
+
+    let mut port = [0u8; 32];
+    let len = child.stdout.as_mut().unwrap().read(&mut port).unwrap();
+    let port: u16 = str::from_utf8(&port[..len]).unwrap().parse().unwrap();
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(b"Hello, world!\n\n").unwrap();
+    drop(stream);
+
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"Hello, world!\n\n");
+}
+
+#[test]
+#[serial]
+fn test_userland_static() {
+    let code = build_static(TEST_STATIC_CODE, false, "test_userland.bin");
+    run_test_static(&mut MemFdExecutable::new("test_userland.bin", &code));
+}
+
+#[test]
+#[serial]
+fn test_userland_static_pie() {
+    let code = build_static(TEST_STATIC_CODE, true, "test_userland_pie.bin");
+    let image = MemFdImage::new("test_userland_pie.bin", &code).unwrap();
+    run_test_static(&mut MemFdExecutable::from_image(
+        "test_userland_pie.bin",
+        &image,
+    ));
+}
+
+#[test]
+#[serial]
+fn test_userland_args_and_env() {
+    let code = build_static(TEST_USERLAND_CODE, true, "test_userland_args.bin");
+    let output = MemFdExecutable::new("args", &code)
+        .args(["one", "two words"])
+        .env("FOO", "bar")
+        .strategies([LaunchStrategy::Userland])
+        .stdout(Stdio::piped())
+        .output()
+        .expect("Failed to map the program");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(
+        str::from_utf8(&output.stdout).unwrap(),
+        "args\none\ntwo words\nFOO=bar\npagesz=1\nrandom=1\n"
+    );
+}
+
+#[test]
+#[serial]
+fn test_userland_dynamic() {
+    let err = MemFdExecutable::new("true", read("/bin/true").unwrap())
+        .strategies([LaunchStrategy::Userland])
+        .spawn()
+        .expect_err("Dynamically linked programs can't be mapped");
+    assert_eq!(
+        MemFdExecError::from_io(&err),
+        Some(&MemFdExecError::Userland(libc::ENOEXEC))
+    );
+}
+
+/// Overwrite a field of the first `PT_LOAD` program header of a 64-bit ELF
+fn patch_first_load(code: &mut [u8], field: usize, value: u64) {
+    let phoff = u64::from_ne_bytes(code[32..40].try_into().unwrap()) as usize;
+    let phentsize = u16::from_ne_bytes(code[54..56].try_into().unwrap()) as usize;
+    let phnum = u16::from_ne_bytes(code[56..58].try_into().unwrap()) as usize;
+    let header = (0..phnum)
+        .map(|i| phoff + i * phentsize)
+        .find(|&at| u32::from_ne_bytes(code[at..at + 4].try_into().unwrap()) == 1)
+        .expect("no PT_LOAD segment");
+    code[header + field..header + field + 8].copy_from_slice(&value.to_ne_bytes());
+}
+
+#[test]
+#[serial]
+fn test_userland_malformed_segment() {
+    const P_VADDR: usize = 16;
+    const P_MEMSZ: usize = 40;
+    let code = build_static(TEST_USERLAND_CODE, true, "test_userland_malformed.bin");
+    for (field, value) in [
+        (P_MEMSZ, u64::MAX),
+        (P_VADDR, u64::MAX - 0x100),
+        (P_MEMSZ, 0),
+    ] {
+        let mut code = code.clone();
+        patch_first_load(&mut code, field, value);
+        let err = MemFdExecutable::new("malformed", &code)
+            .strategies([LaunchStrategy::Userland])
+            .spawn()
+            .expect_err("A malformed PT_LOAD can't be mapped");
+        assert_eq!(
+            MemFdExecError::from_io(&err),
+            Some(&MemFdExecError::Userland(libc::ENOEXEC)),
+            "field {field} = {value:#x}"
+        );
+    }
+}
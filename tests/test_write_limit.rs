@@ -0,0 +1,26 @@
+//! Test that running out of space while writing an image is reported as such. This lives in
+//! its own test binary because the file size limit applies to the whole process.
+
+use std::io::ErrorKind;
+
+use memfd_exec::MemFdImage;
+
+#[test]
+fn test_image_out_of_space() {
+    unsafe {
+        // Exceeding the limit raises SIGXFSZ, which would kill us by default
+        libc::signal(libc::SIGXFSZ, libc::SIG_IGN);
+        let limit = libc::rlimit {
+            rlim_cur: 4096,
+            rlim_max: libc::RLIM_INFINITY,
+        };
+        assert_eq!(libc::setrlimit(libc::RLIMIT_FSIZE, &limit), 0);
+    }
+
+    let code = vec![0x90u8; 64 * 1024];
+    let err = MemFdImage::new("too-big", &code).expect_err("Image should not fit");
+    assert_eq!(err.kind(), ErrorKind::StorageFull, "{err}");
+
+    let err = MemFdImage::from_reader("too-big", &code[..]).expect_err("Image should not fit");
+    assert_eq!(err.kind(), ErrorKind::StorageFull, "{err}");
+}